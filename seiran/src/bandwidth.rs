@@ -0,0 +1,115 @@
+use crate::schedule::Window;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A limit for a time of the week, e.g. `{ days = ["mon", "fri"], start = "08:00", end = "18:00", limit = 200000 }`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Rule {
+    #[serde(flatten)]
+    window: Window,
+    /// bytes per second while the window is open
+    limit: u64,
+}
+
+/// Cap on the bytes per second of all downloads together, `[bandwidth]` in the config.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Bandwidth {
+    /// outside every rule, unlimited when unset
+    #[serde(default)]
+    limit: Option<u64>,
+    /// the first rule open at the time wins
+    #[serde(default)]
+    rules: Vec<Rule>,
+}
+
+impl Bandwidth {
+    /// Bytes per second allowed at `now`, `None` for no limit.
+    pub fn limit(&self, now: DateTime<Utc>) -> Option<u64> {
+        match self.rules.iter().find(|rule| rule.window.contains(now)) {
+            Some(rule) => Some(rule.limit),
+            None => self.limit,
+        }
+    }
+}
+
+struct Bucket {
+    /// bytes that may go right away, negative when owed by downloads already waiting
+    available: f64,
+    at: Instant,
+}
+
+/// Token bucket shared by the concurrent downloads of a sync, holding a second worth of bytes at most.
+pub struct Limiter {
+    bandwidth: Bandwidth,
+    bucket: Mutex<Bucket>,
+}
+
+impl Limiter {
+    pub fn new(bandwidth: Bandwidth) -> Self {
+        Self {
+            bandwidth,
+            bucket: Mutex::new(Bucket {
+                available: 0.0,
+                at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until `bytes` more fit in the limit.
+    pub async fn consume(&self, bytes: u64) {
+        let rate = match self.bandwidth.limit(Utc::now()) {
+            Some(rate) => rate.max(1) as f64,
+            None => return,
+        };
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            bucket.available = (bucket.available + now.duration_since(bucket.at).as_secs_f64() * rate).min(rate);
+            bucket.at = now;
+            // take the bytes now and sleep off the debt, later callers queue behind it
+            bucket.available -= bytes as f64;
+            Duration::from_secs_f64((-bucket.available).max(0.0) / rate)
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn limit_by_time() -> anyhow::Result<()> {
+        let bandwidth: Bandwidth = toml::from_str(
+            r#"
+            limit = 4000
+            [[rules]]
+            start = "08:00"
+            end = "18:00"
+            timezone = "UTC"
+            limit = 1000
+            "#,
+        )?;
+        let at = |hour| Utc.with_ymd_and_hms(2021, 10, 4, hour, 0, 0).unwrap();
+        assert_eq!(Some(1000), bandwidth.limit(at(9)));
+        assert_eq!(Some(4000), bandwidth.limit(at(20)));
+        assert_eq!(None, Bandwidth::default().limit(at(9)));
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let limiter = Limiter::new(Bandwidth {
+            limit: Some(4000),
+            rules: Vec::new(),
+        });
+        let start = Instant::now();
+        // two downloads sharing the limit
+        rt.block_on(futures_util::future::join(limiter.consume(1000), limiter.consume(1000)));
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::Result;
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io, path,
+    time::{Duration, SystemTime},
+};
+
+fn keep_last() -> usize {
+    1
+}
+
+/// Limits on `cache_dir`, `[cache]` in the config, unlimited when unset but for `keep_last`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Policy {
+    /// total bytes of the cache, the oldest files go first
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// seconds since a file was downloaded
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// installed versions to keep per object name, the installed one always stays; 0 keeps them all
+    #[serde(default = "keep_last")]
+    pub keep_last: usize,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            max_bytes: None,
+            max_age: None,
+            keep_last: keep_last(),
+        }
+    }
+}
+
+/// A file in the cache, installed versions are named like their store entry.
+#[derive(Debug)]
+pub struct Entry {
+    pub file: String,
+    /// file name the object is installed as
+    pub name: String,
+    /// hex content md5 of an installed version, `None` for a download not installed yet or from older seiran
+    pub version: Option<String>,
+    pub bytes: u64,
+    pub modified: SystemTime,
+}
+
+/// What [`gc`] removed.
+#[derive(Default, Debug)]
+pub struct Collected {
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+fn parse(file: &str) -> (String, Option<String>) {
+    match file.split_once('-') {
+        Some((version, name)) if version.len() == 32 && version.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            (name.to_owned(), Some(version.to_owned()))
+        }
+        _ => (file.to_owned(), None),
+    }
+}
+
+/// Files in `cache_dir`, oldest first.
+pub fn ls(cache_dir: &path::Path) -> Result<Vec<Entry>> {
+    let dir = match fs::read_dir(cache_dir) {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut entries = Vec::new();
+    for entry in dir {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        // patches live in their own dir and are removed once applied
+        if !metadata.is_file() {
+            continue;
+        }
+        let file = entry.file_name().to_string_lossy().into_owned();
+        let (name, version) = parse(&file);
+        entries.push(Entry {
+            file,
+            name,
+            version,
+            bytes: metadata.len(),
+            modified: metadata.modified()?,
+        });
+    }
+    entries.sort_by_key(|entry| entry.modified);
+    Ok(entries)
+}
+
+/// Remove cache files beyond `policy`, never those in `protected`.
+pub fn gc(cache_dir: &path::Path, policy: &Policy, protected: &HashSet<String>, now: SystemTime) -> Result<Collected> {
+    let entries = ls(cache_dir)?;
+    let mut remove = vec![false; entries.len()];
+    if let Some(max_age) = policy.max_age {
+        let max_age = Duration::from_secs(max_age);
+        for (i, entry) in entries.iter().enumerate() {
+            remove[i] |= now.duration_since(entry.modified).unwrap_or_default() > max_age;
+        }
+    }
+    if policy.keep_last > 0 {
+        let mut kept: HashMap<&str, usize> = HashMap::new();
+        // newest first
+        for (i, entry) in entries.iter().enumerate().rev() {
+            if entry.version.is_some() {
+                let kept = kept.entry(&entry.name).or_default();
+                *kept += 1;
+                remove[i] |= *kept > policy.keep_last;
+            }
+        }
+    }
+    for (i, entry) in entries.iter().enumerate() {
+        remove[i] &= !protected.contains(&entry.file);
+    }
+    if let Some(max_bytes) = policy.max_bytes {
+        let mut total: u64 = entries
+            .iter()
+            .zip(&remove)
+            .filter(|(_, remove)| !**remove)
+            .map(|(entry, _)| entry.bytes)
+            .sum();
+        for (i, entry) in entries.iter().enumerate() {
+            if total <= max_bytes {
+                break;
+            }
+            if !remove[i] && !protected.contains(&entry.file) {
+                remove[i] = true;
+                total -= entry.bytes;
+            }
+        }
+    }
+    let mut collected = Collected::default();
+    for (entry, _) in entries.iter().zip(remove).filter(|(_, remove)| *remove) {
+        log::debug!("remove {} from cache", entry.file);
+        fs::remove_file(cache_dir.join(&entry.file))?;
+        collected.entries += 1;
+        collected.bytes += entry.bytes;
+    }
+    Ok(collected)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn policy() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("seiran-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let version = |i: u8| format!("{:032x}-ccc", i);
+        let written = |i: u64| SystemTime::UNIX_EPOCH + Duration::from_secs(i * 60);
+        for i in 0..4 {
+            fs::write(dir.join(version(i)), [0u8; 10])?;
+            fs::File::options()
+                .write(true)
+                .open(dir.join(version(i)))?
+                .set_modified(written(i.into()))?;
+        }
+        fs::write(dir.join("ddd"), [0u8; 10])?;
+        let protected = HashSet::from_iter([version(0)]);
+        let keep_last = Policy {
+            keep_last: 2,
+            ..Default::default()
+        };
+        let collected = gc(&dir, &keep_last, &protected, SystemTime::now())?;
+        assert_eq!((1, 10), (collected.entries, collected.bytes));
+        assert!(dir.join(version(0)).exists() && !dir.join(version(1)).exists());
+        let max_bytes = Policy {
+            max_bytes: Some(25),
+            keep_last: 0,
+            ..Default::default()
+        };
+        gc(&dir, &max_bytes, &protected, SystemTime::now())?;
+        let left: Vec<_> = ls(&dir)?.into_iter().map(|entry| entry.file).collect();
+        assert_eq!(vec![version(0), "ddd".to_owned()], left);
+        assert_eq!(1, toml::from_str::<Policy>("max_age = 60")?.keep_last);
+        assert_eq!(0, toml::from_str::<Policy>("keep_last = 0")?.keep_last);
+        let max_age = Policy {
+            max_age: Some(60),
+            keep_last: 0,
+            ..Default::default()
+        };
+        gc(
+            &dir,
+            &max_age,
+            &HashSet::new(),
+            SystemTime::now() + Duration::from_secs(120),
+        )?;
+        assert!(ls(&dir)?.is_empty());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
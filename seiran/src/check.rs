@@ -1,19 +1,76 @@
-use crate::meta;
-use colored::Colorize;
+use crate::{
+    meta,
+    report::{self, Event, Reporter, Step},
+};
 use md5::Digest;
 use std::{
     fs, io,
-    io::{Seek, SeekFrom, Write},
+    io::{Seek, SeekFrom},
+    os::unix::fs::MetadataExt,
+    path,
 };
-pub fn check_md5_sum(mut file: fs::File, meta: &meta::Meta) -> anyhow::Result<bool> {
-    print!("Check {}...", meta.name().cyan());
-    io::stdout().flush().unwrap();
+
+pub(crate) fn md5_sum(mut file: &fs::File) -> io::Result<String> {
     let mut hasher = md5::Md5::new();
     file.seek(SeekFrom::Start(0))?;
     io::copy(&mut file, &mut hasher)?;
     let bin_md5 = hasher.finalize();
-    let literally_md5 = base64::encode(bin_md5);
+    Ok(base64::encode(bin_md5))
+}
+
+/// Compare a computed md5 with the listed one.
+pub fn check_md5(literally_md5: &str, meta: &meta::Meta, reporter: &dyn Reporter) -> bool {
+    let name = meta.name();
+    let step = Step::Check { name: &name };
+    reporter.report(&Event::Begin(step));
+    log::debug!("{}: md5 {}, listed {}", name, literally_md5, meta.md5_hash);
     let res = literally_md5 == meta.md5_hash;
-    println!("{}", if res { "OK".green() } else { "Failed".red() });
-    Ok(res)
+    if res {
+        reporter.report(&Event::Done(step));
+    } else {
+        let error = "md5 mismatch.".to_owned();
+        reporter.report(&Event::Failed { step, error });
+    }
+    res
+}
+
+pub fn check_md5_sum(file: &fs::File, meta: &meta::Meta, reporter: &dyn Reporter) -> anyhow::Result<bool> {
+    Ok(check_md5(&md5_sum(file)?, meta, reporter))
+}
+
+/// closest existing ancestor, since cache and install dirs may not be created yet
+fn existing_ancestor(dir: &path::Path) -> &path::Path {
+    dir.ancestors()
+        .find(|dir| dir.exists())
+        .unwrap_or_else(|| path::Path::new("/"))
+}
+
+/// Make sure every dir has room for the whole delta before downloading anything.
+/// Dirs sharing a filesystem must fit one copy of the delta each, decoded.
+pub fn check_free_space(delta: &[meta::Meta], dirs: &[&path::Path], reporter: &dyn Reporter) -> anyhow::Result<()> {
+    let total: u64 = delta.iter().map(meta::Meta::content_size).sum();
+    report::step(reporter, Step::CheckSpace { bytes: total }, || {
+        let mut required: Vec<(u64, &path::Path, u64)> = Vec::new();
+        for dir in dirs {
+            let dir = existing_ancestor(dir);
+            let dev = fs::metadata(dir)?.dev();
+            match required.iter_mut().find(|(d, ..)| *d == dev) {
+                Some((.., size)) => *size += total,
+                None => required.push((dev, dir, total)),
+            }
+        }
+        for (_, dir, size) in required {
+            let available = fs2::available_space(dir)?;
+            log::debug!("{}: {} bytes required, {} available", dir.display(), size, available);
+            if available < size {
+                return Err(anyhow::Error::msg(format!(
+                    "{} needs {} bytes but only {} available.",
+                    dir.display(),
+                    size,
+                    available
+                )));
+            }
+        }
+        Ok(())
+    })
 }
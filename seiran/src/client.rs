@@ -0,0 +1,231 @@
+use anyhow::Result;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, OwnedTrustAnchor, PrivateKey, RootCertStore, ServerName,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs, io, path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+fn connect_timeout() -> u64 {
+    30
+}
+
+fn read_timeout() -> u64 {
+    60
+}
+
+/// Proxy, TLS and timeouts of every request, `[http]` in the config.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Http {
+    /// proxy for http and https, default to the HTTP_PROXY and HTTPS_PROXY variables
+    #[serde(default)]
+    proxy: Option<String>,
+    /// comma separated hosts reached without the proxy, like NO_PROXY
+    #[serde(default)]
+    no_proxy: Option<String>,
+    /// PEM files of CAs trusted besides the built in roots
+    #[serde(default)]
+    ca_bundles: Vec<path::PathBuf>,
+    /// PEM with the client certificate chain for mTLS, and its key unless client_key is set
+    #[serde(default)]
+    client_cert: Option<path::PathBuf>,
+    #[serde(default)]
+    client_key: Option<path::PathBuf>,
+    /// hex sha256 of DER server certificates by host name, a host listed must present one of its own
+    #[serde(default)]
+    pins: BTreeMap<String, Vec<String>>,
+    /// seconds to establish a connection
+    #[serde(default = "connect_timeout")]
+    connect_timeout: u64,
+    /// seconds without receiving anything before a request is given up
+    #[serde(default = "read_timeout")]
+    read_timeout: u64,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Self {
+            proxy: None,
+            no_proxy: None,
+            ca_bundles: Vec::new(),
+            client_cert: None,
+            client_key: None,
+            pins: BTreeMap::new(),
+            connect_timeout: connect_timeout(),
+            read_timeout: read_timeout(),
+        }
+    }
+}
+
+fn pem(path: &path::Path) -> Result<Vec<rustls_pemfile::Item>> {
+    let file = fs::File::open(path).map_err(|e| anyhow::Error::msg(format!("{}: {}", path.display(), e)))?;
+    Ok(rustls_pemfile::read_all(&mut io::BufReader::new(file))?)
+}
+
+fn certificates(path: &path::Path) -> Result<Vec<Certificate>> {
+    let certs: Vec<_> = pem(path)?
+        .into_iter()
+        .filter_map(|item| match item {
+            rustls_pemfile::Item::X509Certificate(der) => Some(Certificate(der)),
+            _ => None,
+        })
+        .collect();
+    if certs.is_empty() {
+        return Err(anyhow::Error::msg(format!("No certificate in {}.", path.display())));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &path::Path) -> Result<PrivateKey> {
+    pem(path)?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::RSAKey(der)
+            | rustls_pemfile::Item::PKCS8Key(der)
+            | rustls_pemfile::Item::ECKey(der) => Some(PrivateKey(der)),
+            _ => None,
+        })
+        .ok_or_else(|| anyhow::Error::msg(format!("No private key in {}.", path.display())))
+}
+
+/// Whether the certificate `der` hashes to one of `pins`.
+fn is_pinned(pins: &[String], der: &[u8]) -> bool {
+    let digest = hex::encode(ring::digest::digest(&ring::digest::SHA256, der));
+    pins.iter().any(|pin| pin.eq_ignore_ascii_case(&digest))
+}
+
+/// Whether `der` may serve `server_name`, any certificate may for a host without pins.
+fn allows(pins: &BTreeMap<String, Vec<String>>, server_name: &ServerName, der: &[u8]) -> bool {
+    let host = match server_name {
+        ServerName::DnsName(name) => name.as_ref().to_owned(),
+        ServerName::IpAddress(ip) => ip.to_string(),
+        _ => return true,
+    };
+    match pins.iter().find(|(pinned, _)| pinned.eq_ignore_ascii_case(&host)) {
+        Some((_, pins)) => is_pinned(pins, der),
+        None => true,
+    }
+}
+
+/// The usual verification, then the pin of the server certificate for the hosts that have one.
+///
+/// Only the leaf counts: the intermediates are whatever the server sends, a pinned CA among them proves nothing.
+struct Pinned {
+    verifier: WebPkiVerifier,
+    pins: BTreeMap<String, Vec<String>>,
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.verifier
+            .verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        if !allows(&self.pins, server_name, &end_entity.0) {
+            return Err(rustls::Error::General(format!(
+                "Certificate of {:?} is not pinned.",
+                server_name
+            )));
+        }
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+fn tls(http: &Http) -> Result<rustls::ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(anchor.subject, anchor.spki, anchor.name_constraints)
+    }));
+    for bundle in &http.ca_bundles {
+        for cert in certificates(bundle)? {
+            roots.add(&cert)?;
+        }
+    }
+    let builder = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots.clone());
+    let mut config = match http.client_cert {
+        Some(ref cert) => {
+            let key = private_key(http.client_key.as_deref().unwrap_or(cert))?;
+            builder.with_client_auth_cert(certificates(cert)?, key)?
+        }
+        None => builder.with_no_client_auth(),
+    };
+    if !http.pins.is_empty() {
+        config.dangerous().set_certificate_verifier(Arc::new(Pinned {
+            verifier: WebPkiVerifier::new(roots, None),
+            pins: http.pins.clone(),
+        }));
+    }
+    Ok(config)
+}
+
+/// Requests with the `[http]` settings they were built from, cheap to clone.
+#[derive(Clone)]
+pub struct Client {
+    inner: reqwest::Client,
+    idle_timeout: Duration,
+}
+
+impl Client {
+    pub fn new(http: &Http) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .use_preconfigured_tls(tls(http)?)
+            .connect_timeout(Duration::from_secs(http.connect_timeout));
+        if let Some(ref proxy) = http.proxy {
+            let no_proxy = http.no_proxy.as_deref().and_then(reqwest::NoProxy::from_string);
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?.no_proxy(no_proxy));
+        }
+        Ok(Self {
+            inner: builder.build()?,
+            idle_timeout: Duration::from_secs(http.read_timeout),
+        })
+    }
+
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.inner.get(url)
+    }
+
+    pub fn post(&self, url: &str) -> reqwest::RequestBuilder {
+        self.inner.post(url)
+    }
+
+    /// Longest wait for the next bytes of a response.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pins() {
+        let pins = vec!["BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD".to_owned()];
+        assert!(is_pinned(&pins, b"abc"));
+        assert!(!is_pinned(&pins, b"leaf"));
+        let pins = BTreeMap::from_iter([("mirror.internal".to_owned(), pins)]);
+        let name = |name: &str| ServerName::try_from(name).expect("a valid name");
+        assert!(allows(&pins, &name("Mirror.Internal"), b"abc"));
+        assert!(!allows(&pins, &name("mirror.internal"), b"leaf"));
+        // other hosts keep the usual verification alone
+        assert!(allows(&pins, &name("storage.googleapis.com"), b"leaf"));
+        assert!(tls(&Http {
+            ca_bundles: vec!["/nonexistent.pem".into()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
@@ -1,7 +1,24 @@
-use crate::APPLICATION;
+use crate::{
+    bandwidth::Bandwidth,
+    cache::Policy,
+    client::Http,
+    layers::{Layers, Origin},
+    meta::Meta,
+    mirror::Mirror,
+    order::Object,
+    perm::{self, Permission},
+    report::Reporter,
+    schedule::Window,
+    smoke::SmokeTest,
+    store::{Store, STORE_DIR},
+    webhook::Webhook,
+    APPLICATION,
+};
 use anyhow::Result;
-use serde::Deserialize;
-use std::{borrow::Cow, fs, io::Read, path};
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{borrow::Cow, collections::HashMap, path};
+use toml::{value::Table, Value};
 
 fn cache_dir<'a>() -> Cow<'a, path::Path> {
     dirs::cache_dir().expect("No XDG_CACHE_HOME setted.").into()
@@ -12,46 +29,371 @@ fn data_dir<'a>() -> Cow<'a, path::Path> {
 }
 
 fn bin_dir<'a>() -> Cow<'a, path::Path> {
-    path::Path::new("/usr/local/bin").into()
+    perm::default_install_dir().expect("No HOME setted.").into()
 }
 
-#[derive(Deserialize)]
+/// Defaults as the bottom layer, so `seiran config show` lists them too.
+/// A dir without a default, like without HOME, is only an error when the config leaves it unset.
+fn defaults() -> Table {
+    let mut table = Table::from_iter([
+        ("mirror_cooldown".to_owned(), Value::Integer(mirror_cooldown() as i64)),
+        ("keep_generations".to_owned(), Value::Integer(keep_generations() as i64)),
+        ("patch_prefix".to_owned(), Value::String(patch_prefix().into_owned())),
+    ]);
+    for (key, dir) in [
+        ("cache_dir", dirs::cache_dir()),
+        ("data_dir", dirs::data_dir()),
+        ("install_dir", perm::default_install_dir()),
+    ] {
+        if let Some(dir) = dir {
+            table.insert(key.to_owned(), Value::String(dir.to_string_lossy().into_owned()));
+        }
+    }
+    table
+}
+
+fn mirror_cooldown() -> u64 {
+    600
+}
+
+fn keep_generations() -> usize {
+    3
+}
+
+fn patch_prefix<'a>() -> Cow<'a, str> {
+    "patches/".into()
+}
+
+fn regexes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Regex>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|re| Regex::new(re).map_err(D::Error::custom))
+        .collect()
+}
+
+/// One bucket and where its objects go, `[[source]]` in the config.
+#[derive(Deserialize, Clone)]
+pub struct Source<'a> {
+    /// namespace of the database and cache of this source
+    name: Cow<'a, str>,
+    #[serde(flatten)]
+    backend: Mirror<'a>,
+    #[serde(default)]
+    mirrors: Vec<Mirror<'a>>,
+    /// default to the top level install_dir
+    #[serde(default)]
+    install_dir: Option<Cow<'a, path::Path>>,
+    #[serde(default, deserialize_with = "regexes")]
+    include: Vec<Regex>,
+    #[serde(default, deserialize_with = "regexes")]
+    exclude: Vec<Regex>,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct Config<'a> {
-    /// https://storage.googleapis.com/storage/v1/
+    /// https://storage.googleapis.com/storage/v1/, unused with `[[source]]`
+    #[serde(default)]
     api_endpoint: Cow<'a, str>,
     /// example_bucket
+    #[serde(default)]
     bucket_name: Cow<'a, str>,
+    /// OAuth2 bearer token, for private buckets
+    #[serde(default)]
+    token: Option<Cow<'a, str>>,
+    /// cap on the download rate, by time of day
+    #[serde(default)]
+    bandwidth: Bandwidth,
+    /// proxy, TLS and timeouts of every request
+    #[serde(default)]
+    http: Http,
+    /// fallbacks tried in order when the primary endpoint fails
+    #[serde(default)]
+    mirrors: Vec<Mirror<'a>>,
+    /// seconds to skip a failed mirror for
+    #[serde(default = "mirror_cooldown")]
+    mirror_cooldown: u64,
+    /// objects under this prefix are binary patches between versions, never installed themselves
+    #[serde(default = "patch_prefix")]
+    patch_prefix: Cow<'a, str>,
     /// default to XDG_CACHE_HOME
     #[serde(default = "cache_dir")]
     cache_dir: Cow<'a, path::Path>,
+    /// limits on the cache, collected after each successful sync
+    #[serde(default)]
+    cache: Policy,
     #[serde(default = "data_dir")]
     data_dir: Cow<'a, path::Path>,
     #[serde(default = "bin_dir")]
     install_dir: Cow<'a, path::Path>,
+    /// content-addressed store the installed files link to, default to `store` in the data dir;
+    /// whoever runs the installed files needs to traverse it, as /var/lib/seiran/store for a system install
+    #[serde(default)]
+    store_dir: Option<Cow<'a, path::Path>>,
+    /// regexes of object names to sync, every object when empty
+    #[serde(default, deserialize_with = "regexes")]
+    include: Vec<Regex>,
+    /// regexes of object names to leave out
+    #[serde(default, deserialize_with = "regexes")]
+    exclude: Vec<Regex>,
+    /// buckets synced in one run, replacing the top level api_endpoint, bucket_name, token and mirrors
+    #[serde(default)]
+    source: Vec<Source<'a>>,
+    /// name of the source this config was derived for
+    #[serde(skip)]
+    namespace: Option<Cow<'a, str>>,
+    /// object with rollout percentages by object name, overriding the `seiran-rollout` metadata
+    #[serde(default)]
+    rollout_manifest: Option<Cow<'a, str>>,
+    /// stable identifier placing this host in rollouts, default to the machine id
+    #[serde(default)]
+    host_id: Option<Cow<'a, str>>,
+    /// times installs are allowed in, any time when empty; outside them updates are only cached
+    #[serde(default)]
+    windows: Vec<Window>,
+    /// file names installed as soon as they are fetched, regardless of windows
+    #[serde(default)]
+    urgent: Vec<String>,
+    /// generations `seiran gc` keeps the store entries of
+    #[serde(default = "keep_generations")]
+    keep_generations: usize,
+    /// owner and mode of installed files, the first matching rule applies
+    #[serde(default)]
+    permissions: Vec<Permission>,
+    /// smoke tests by installed file name, a failing binary is not installed
+    #[serde(default)]
+    smoke_tests: HashMap<String, SmokeTest>,
+    /// node-exporter textfile collector file written after each run, /var/lib/node_exporter/textfile/seiran.prom
+    #[serde(default)]
+    metrics_file: Option<Cow<'a, path::Path>>,
+    /// install order and groups by file name, over the `seiran-after` and `seiran-group` metadata
+    #[serde(default)]
+    objects: HashMap<String, Object>,
+    /// URLs told about the objects each sync installed or failed on
+    #[serde(default)]
+    webhooks: Vec<Webhook>,
 }
 
 impl<'a> Config<'a> {
     pub fn cache_dir(&self) -> Cow<'a, path::Path> {
-        self.cache_dir.join(APPLICATION).into()
+        let cache_dir = self.cache_dir.join(APPLICATION);
+        match self.namespace {
+            Some(ref namespace) => cache_dir.join(namespace.as_ref()).into(),
+            None => cache_dir.into(),
+        }
     }
 
     pub fn data_dir(&self) -> Cow<'a, path::Path> {
-        self.data_dir.join(APPLICATION).into()
+        let data_dir = self.data_dir.join(APPLICATION);
+        match self.namespace {
+            Some(ref namespace) => data_dir.join(namespace.as_ref()).into(),
+            None => data_dir.into(),
+        }
+    }
+
+    /// Store of the verified objects, under the data dir unless configured elsewhere.
+    pub fn store_dir(&self) -> Cow<'a, path::Path> {
+        match (&self.store_dir, &self.namespace) {
+            (Some(store_dir), Some(namespace)) => store_dir.join(namespace.as_ref()).into(),
+            (Some(store_dir), None) => store_dir.clone(),
+            (None, _) => self.data_dir().join(STORE_DIR).into(),
+        }
+    }
+
+    pub fn store(&self) -> Store<'a> {
+        Store::new(self.data_dir(), self.store_dir())
+    }
+
+    pub fn cache_policy(&self) -> &Policy {
+        &self.cache
+    }
+
+    /// Name of the source, `None` for a config without `[[source]]`.
+    pub fn name(&self) -> Option<&str> {
+        self.namespace.as_deref()
+    }
+
+    /// One config per source, or this one without `[[source]]`.
+    pub fn sources(&self) -> Vec<Config<'a>> {
+        if self.source.is_empty() {
+            return vec![self.clone()];
+        }
+        self.source
+            .iter()
+            .map(|source| Config {
+                api_endpoint: source.backend.api_endpoint.clone(),
+                bucket_name: source.backend.bucket_name.clone(),
+                token: source.backend.token.clone(),
+                mirrors: source.mirrors.clone(),
+                install_dir: source.install_dir.clone().unwrap_or_else(|| self.install_dir.clone()),
+                include: source.include.clone(),
+                exclude: source.exclude.clone(),
+                source: Vec::new(),
+                namespace: Some(source.name.clone()),
+                ..self.clone()
+            })
+            .collect()
+    }
+
+    /// Whether the object `name` passes the include and exclude filters.
+    pub fn is_wanted(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|re| re.is_match(name)))
+            && !self.exclude.iter().any(|re| re.is_match(name))
     }
 
     pub fn install_dir(&self) -> Cow<'a, path::Path> {
         self.install_dir.clone()
     }
 
-    pub fn list_api(self) -> Cow<'a, str> {
-        self.api_endpoint + "b/" + self.bucket_name + "/o"
+    pub fn list_api(&self) -> Cow<'a, str> {
+        self.primary().list_api()
+    }
+
+    pub fn primary(&self) -> Mirror<'a> {
+        Mirror {
+            api_endpoint: self.api_endpoint.clone(),
+            bucket_name: self.bucket_name.clone(),
+            token: self.token.clone(),
+        }
     }
 
+    /// The primary endpoint followed by the mirrors.
+    pub fn mirrors(&self) -> Vec<Mirror<'a>> {
+        let mut mirrors = vec![self.primary()];
+        mirrors.extend(self.mirrors.iter().cloned());
+        mirrors
+    }
+
+    pub fn bandwidth(&self) -> &Bandwidth {
+        &self.bandwidth
+    }
+
+    pub fn http(&self) -> &Http {
+        &self.http
+    }
+
+    pub fn mirror_cooldown(&self) -> u64 {
+        self.mirror_cooldown
+    }
+
+    pub fn rollout_manifest(&self) -> Option<&str> {
+        self.rollout_manifest.as_deref()
+    }
+
+    pub fn host_id(&self) -> Option<&str> {
+        self.host_id.as_deref()
+    }
+
+    pub fn windows(&self) -> &[Window] {
+        &self.windows
+    }
+
+    pub fn is_urgent(&self, name: &str) -> bool {
+        self.urgent.iter().any(|urgent| urgent == name)
+    }
+
+    pub fn keep_generations(&self) -> usize {
+        self.keep_generations
+    }
+
+    /// Owner and mode for the installed file `name`, 0755 when no rule matches.
+    pub fn permission(&self, name: &str) -> Cow<'_, Permission> {
+        match self.permissions.iter().find(|permission| permission.is_match(name)) {
+            Some(permission) => Cow::Borrowed(permission),
+            None => Cow::Owned(Permission::default()),
+        }
+    }
+
+    pub fn smoke_test(&self, name: &str) -> Option<&SmokeTest> {
+        self.smoke_tests.get(name)
+    }
+
+    pub fn objects(&self) -> &HashMap<String, Object> {
+        &self.objects
+    }
+
+    /// Dependencies and group of `meta`.
+    pub fn object(&self, meta: &Meta) -> Object {
+        Object::of(meta, self.objects.get(&meta.name()))
+    }
+
+    pub fn webhooks(&self) -> &[Webhook] {
+        &self.webhooks
+    }
+
+    pub fn metrics_file(&self) -> Option<&path::Path> {
+        self.metrics_file.as_deref()
+    }
+
+    pub fn patch_prefix(&self) -> &str {
+        &self.patch_prefix
+    }
+
+    /// Load `file` alone, without the system config or environment.
     pub fn from_file(file: &path::Path) -> Result<Self> {
-        let mut file = fs::File::open(file)?;
-        let mut content = String::new();
-        file.read_to_string(&mut content)?;
-        Ok(toml::from_str(&content)?)
+        let mut layers = Layers::new(defaults());
+        layers.merge_file(file)?;
+        Self::from_layers(&layers)
+    }
+
+    /// Load the system config and drop-ins, `file` or the per-user config, and `SEIRAN_*` variables.
+    pub fn load(file: Option<&path::Path>, reporter: &dyn Reporter) -> Result<(Self, Layers)> {
+        let user = file.map_or_else(Self::default_config_path, ToOwned::to_owned);
+        let layers = Layers::load(defaults(), &user, file.is_some(), reporter)?;
+        Ok((Self::from_layers(&layers)?, layers))
+    }
+
+    pub fn from_layers(layers: &Layers) -> Result<Self> {
+        let error = |key: String, e: &dyn std::fmt::Display| {
+            anyhow::Error::msg(format!("{}: {}: {}", layers.origin(&key), key, e))
+        };
+        let mut table = layers.table().clone();
+        let (config, unknown): (Self, _) = loop {
+            let mut track = serde_path_to_error::Track::new();
+            let mut unknown = Vec::new();
+            let de = serde_path_to_error::Deserializer::new(Value::Table(table.clone()), &mut track);
+            match serde_ignored::deserialize(de, |key| unknown.push(key.to_string())) {
+                Ok(config) => break (config, unknown),
+                Err(e) => {
+                    // a variable reading as a number or bool may be meant as a string
+                    let key = track.path().to_string();
+                    if !layers.stringify_env(&mut table, &key) {
+                        return Err(error(key, &e));
+                    }
+                }
+            }
+        };
+        for key in unknown {
+            match layers.origin(&key) {
+                // the environment may hold SEIRAN_ variables of other programs
+                Origin::Env(var) => log::warn!("{} is not a config key, ignored", var),
+                _ => return Err(error(key, &"unknown key")),
+            }
+        }
+        match config.validate() {
+            Some((key, e)) => Err(error(key, &e)),
+            None => Ok(config),
+        }
+    }
+
+    /// The first invalid value, as its key and what is wrong.
+    fn validate(&self) -> Option<(String, String)> {
+        if self.source.is_empty() {
+            for (key, value) in [("api_endpoint", &self.api_endpoint), ("bucket_name", &self.bucket_name)] {
+                if value.is_empty() {
+                    return Some((key.into(), "required without [[source]]".into()));
+                }
+            }
+        }
+        for (i, source) in self.source.iter().enumerate() {
+            let key = format!("source[{}].name", i);
+            if source.name.is_empty() || source.name.contains(['/', '.']) {
+                return Some((key, format!("invalid source name {:?}", source.name)));
+            }
+            if self.source[..i].iter().any(|other| other.name == source.name) {
+                return Some((key, format!("duplicate source {}", source.name)));
+            }
+        }
+        None
     }
 
     pub fn default_config_path() -> path::PathBuf {
@@ -61,3 +403,46 @@ impl<'a> Config<'a> {
             .join("config.toml")
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sources() -> anyhow::Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+                data_dir = "/var/lib"
+                install_dir = "/opt/bin"
+
+                [[source]]
+                name = "internal"
+                api_endpoint = "http://a/"
+                bucket_name = "tools"
+                exclude = ["^patches/"]
+
+                [[source]]
+                name = "vendor"
+                api_endpoint = "http://b/"
+                bucket_name = "vendor"
+                install_dir = "/opt/vendor/bin"
+                include = ["^linux-amd64/"]
+            "#,
+        )?;
+        assert!(config.validate().is_none());
+        let sources = config.sources();
+        assert_eq!(Some("internal"), sources[0].name());
+        assert_eq!(path::Path::new("/var/lib/seiran/vendor"), sources[1].data_dir());
+        assert_eq!(path::Path::new("/opt/bin"), sources[0].install_dir());
+        assert_eq!(path::Path::new("/opt/vendor/bin"), sources[1].install_dir());
+        assert!(sources[0].is_wanted("tools/a") && !sources[0].is_wanted("patches/a"));
+        assert!(sources[1].is_wanted("linux-amd64/a") && !sources[1].is_wanted("darwin/a"));
+        assert_eq!(path::Path::new("/var/lib/seiran/vendor/store"), sources[1].store_dir());
+        let config = Config {
+            store_dir: Some(path::Path::new("/srv/store").into()),
+            ..config
+        };
+        assert_eq!(path::Path::new("/srv/store/vendor"), config.sources()[1].store_dir());
+        Ok(())
+    }
+}
@@ -3,21 +3,43 @@ use anyhow::Result;
 use std::{borrow::Cow, fs, path};
 
 const DB: &str = "data.json";
+/// objects verified into the cache, waiting for a window to install them
+const STAGED: &str = "staged.json";
 
 pub fn load(data_dir: Cow<'_, path::Path>) -> Result<MetaTable> {
-    let db_path = data_dir.join(DB);
+    load_from(data_dir, DB)
+}
+
+pub fn save(data_dir: Cow<'_, path::Path>, data: Cow<'_, MetaTable>) -> Result<()> {
+    save_to(data_dir, DB, data)
+}
+
+pub fn load_staged(data_dir: Cow<'_, path::Path>) -> Result<MetaTable> {
+    load_from(data_dir, STAGED)
+}
+
+pub fn save_staged(data_dir: Cow<'_, path::Path>, data: Cow<'_, MetaTable>) -> Result<()> {
+    save_to(data_dir, STAGED, data)
+}
+
+fn load_from(data_dir: Cow<'_, path::Path>, file: &str) -> Result<MetaTable> {
+    let db_path = data_dir.join(file);
     let db = fs::File::open(db_path)?;
     Ok(serde_json::from_reader(&db)?)
 }
 
-pub fn save(data_dir: Cow<'_, path::Path>, data: Cow<'_, MetaTable>) -> Result<()> {
+fn save_to(data_dir: Cow<'_, path::Path>, file: &str, data: Cow<'_, MetaTable>) -> Result<()> {
     fs::create_dir_all(&data_dir).ok();
-    let db_path = data_dir.join(DB);
-    let db = fs::File::with_options()
+    let db_path = data_dir.join(file);
+    // write aside then rename, so an interrupted run never leaves a truncated database
+    let tmp_path = db_path.with_extension("json.tmp");
+    let db = fs::File::options()
         .truncate(true)
         .write(true)
         .create(true)
-        .open(db_path)?;
-    serde_json::to_writer_pretty(db, &data)?;
+        .open(&tmp_path)?;
+    serde_json::to_writer_pretty(&db, &data)?;
+    db.sync_all()?;
+    fs::rename(tmp_path, db_path)?;
     Ok(())
 }
@@ -0,0 +1,308 @@
+use crate::{check, client::Client, database, meta::MetaTable, mirror::Mirror, report::Severity, Config};
+use chrono::{DateTime, Utc};
+use nix::unistd::{self, AccessFlags};
+use std::{fs, io, os::unix::fs::MetadataExt, path};
+
+/// clock difference to the endpoint worth a warning, in seconds
+const MAX_SKEW: i64 = 60;
+
+/// What `seiran doctor` found about one part of the setup.
+#[derive(Debug)]
+pub struct Finding {
+    pub check: String,
+    pub severity: Severity,
+    pub detail: String,
+    /// what to do about it
+    pub hint: Option<String>,
+}
+
+impl Finding {
+    pub fn ok(check: &str, detail: impl ToString) -> Self {
+        Self {
+            check: check.to_owned(),
+            severity: Severity::Ok,
+            detail: detail.to_string(),
+            hint: None,
+        }
+    }
+
+    pub fn warning(check: &str, detail: impl ToString, hint: impl ToString) -> Self {
+        Self {
+            severity: Severity::Warning,
+            hint: Some(hint.to_string()),
+            ..Self::ok(check, detail)
+        }
+    }
+
+    pub fn error(check: &str, detail: impl ToString, hint: impl ToString) -> Self {
+        Self {
+            severity: Severity::Error,
+            hint: Some(hint.to_string()),
+            ..Self::ok(check, detail)
+        }
+    }
+}
+
+fn is_writable(dir: &path::Path) -> bool {
+    unistd::access(dir, AccessFlags::W_OK | AccessFlags::X_OK).is_ok()
+}
+
+/// `dir` exists or can be created, is owned by this user and writable.
+fn check_dir(check: &str, dir: &path::Path) -> Finding {
+    let user = unistd::geteuid();
+    let metadata = match fs::metadata(dir) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let parent = dir.ancestors().skip(1).find(|parent| parent.exists());
+            return match parent {
+                Some(parent) if is_writable(parent) => Finding::warning(
+                    check,
+                    format!("{} does not exist", dir.display()),
+                    "it is created on the next sync",
+                ),
+                Some(parent) => Finding::error(
+                    check,
+                    format!(
+                        "{} does not exist and {} is not writable",
+                        dir.display(),
+                        parent.display()
+                    ),
+                    format!("create {} owned by uid {}", dir.display(), user),
+                ),
+                None => Finding::error(
+                    check,
+                    format!("{} is not reachable", dir.display()),
+                    "use an absolute path",
+                ),
+            };
+        }
+        Err(e) => {
+            return Finding::error(
+                check,
+                format!("{}: {}", dir.display(), e),
+                "check the permissions above it",
+            )
+        }
+    };
+    if !metadata.is_dir() {
+        return Finding::error(
+            check,
+            format!("{} is not a directory", dir.display()),
+            "move it away or point the config elsewhere",
+        );
+    }
+    if metadata.uid() != user.as_raw() {
+        return Finding::error(
+            check,
+            format!(
+                "{} is owned by uid {}, not by uid {}",
+                dir.display(),
+                metadata.uid(),
+                user
+            ),
+            format!("chown {} {}", user, dir.display()),
+        );
+    }
+    if !is_writable(dir) {
+        return Finding::error(
+            check,
+            format!("{} is not writable", dir.display()),
+            format!("chmod u+rwx {}", dir.display()),
+        );
+    }
+    Finding::ok(check, dir.display())
+}
+
+/// Reachability and credentials of `mirror`, and the skew of the clock against its `Date`.
+async fn check_endpoint(mirror: &Mirror<'_>, client: &Client, findings: &mut Vec<Finding>) {
+    let uri = mirror.list_api();
+    let res = match mirror.get(client, &uri).timeout(client.idle_timeout()).send().await {
+        Ok(res) => res,
+        Err(e) => {
+            let detail = format!("{}: {}", uri, anyhow::Error::from(e).chain().last().unwrap());
+            let hint = "check the network, the [http] proxy and TLS settings";
+            findings.push(Finding::error("endpoint", detail, hint));
+            return;
+        }
+    };
+    let date = res
+        .headers()
+        .get(reqwest::header::DATE)
+        .and_then(|date| DateTime::parse_from_rfc2822(date.to_str().ok()?).ok());
+    if let Some(date) = date {
+        let skew = Utc::now().timestamp() - date.timestamp();
+        findings.push(match skew.abs() > MAX_SKEW {
+            true => Finding::warning(
+                "clock",
+                format!(
+                    "{}s {} {}",
+                    skew.abs(),
+                    if skew > 0 { "ahead of" } else { "behind" },
+                    uri
+                ),
+                "sync the clock with NTP, windows, rollouts and TLS depend on it",
+            ),
+            false => Finding::ok("clock", format!("within {}s of {}", MAX_SKEW, uri)),
+        });
+    }
+    let status = res.status();
+    match status.as_u16() {
+        401 | 403 => {
+            let hint = match mirror.token {
+                Some(_) => "the token is expired or has no access to the bucket, renew it",
+                None => "the bucket is private, set token",
+            };
+            findings.push(Finding::error("credentials", format!("{}: {}", uri, status), hint));
+        }
+        404 => findings.push(Finding::error(
+            "endpoint",
+            format!("{}: bucket not found", uri),
+            "check api_endpoint and bucket_name",
+        )),
+        _ if !status.is_success() => findings.push(Finding::error(
+            "endpoint",
+            format!("{}: {}", uri, status),
+            "try again later or use a mirror",
+        )),
+        _ => {
+            if mirror.token.is_some() {
+                findings.push(Finding::ok("credentials", format!("token accepted by {}", uri)));
+            }
+            findings.push(match res.json::<MetaTable>().await {
+                Ok(listing) => Finding::ok("endpoint", format!("{}: {} object(s)", uri, listing.iter().count())),
+                Err(e) => Finding::error(
+                    "endpoint",
+                    format!("{}: listing not understood: {}", uri, e),
+                    "check api_endpoint points at the JSON API",
+                ),
+            });
+        }
+    }
+}
+
+/// The database and the generations parse, and the installed files are the recorded versions.
+fn check_database(config: &Config<'_>, findings: &mut Vec<Finding>) {
+    let data_dir = config.data_dir();
+    let hint = "remove it, every object is installed again on the next sync";
+    let installed = match database::load(data_dir.clone()) {
+        Ok(installed) => installed,
+        Err(e)
+            if e.downcast_ref::<io::Error>()
+                .is_some_and(|e| e.kind() == io::ErrorKind::NotFound) =>
+        {
+            findings.push(Finding::ok("database", "nothing installed yet"));
+            MetaTable::default()
+        }
+        Err(e) => {
+            findings.push(Finding::error("database", format!("data.json: {}", e), hint));
+            return;
+        }
+    };
+    if data_dir.join("staged.json").exists() {
+        if let Err(e) = database::load_staged(data_dir.clone()) {
+            findings.push(Finding::error("database", format!("staged.json: {}", e), hint));
+        }
+    }
+    if let Err(e) = config.store().generations() {
+        findings.push(Finding::error(
+            "database",
+            format!("generations.json: {}", e),
+            "remove it, the next sync starts a new generation",
+        ));
+    }
+    let install_dir = config.install_dir();
+    let mut intact = 0;
+    for meta in installed.iter() {
+        let name = meta.name();
+        let file = match meta.install_path() {
+            Ok(path) => install_dir.join(path),
+            Err(e) => {
+                findings.push(Finding::error(
+                    "installed",
+                    e,
+                    "fix the metadata of the object and sync again",
+                ));
+                continue;
+            }
+        };
+        match fs::File::open(&file).and_then(|file| check::md5_sum(&file)) {
+            Ok(md5) if md5 == meta.content_md5() => intact += 1,
+            Ok(_) => findings.push(Finding::error(
+                "installed",
+                format!("{} differs from the version installed", file.display()),
+                format!("remove {} and its record in data.json to install it again", name),
+            )),
+            Err(e) => findings.push(Finding::error(
+                "installed",
+                format!("{}: {}", file.display(), e),
+                format!("remove the record of {} in data.json to install it again", name),
+            )),
+        }
+    }
+    if intact > 0 {
+        findings.push(Finding::ok("installed", format!("{} file(s) as recorded", intact)));
+    }
+}
+
+/// Diagnose the dirs, endpoints and database of `config`.
+///
+/// The endpoints are only tried with a `client`, one without the `[http]` settings would tell nothing about them.
+pub async fn doctor(config: &Config<'_>, client: Option<&Client>) -> Vec<Finding> {
+    let mut findings = vec![
+        check_dir("cache dir", &config.cache_dir()),
+        check_dir("data dir", &config.data_dir()),
+        check_dir("install dir", &config.install_dir()),
+    ];
+    if let Some(client) = client {
+        for mirror in config.mirrors() {
+            check_endpoint(&mirror, client, &mut findings).await;
+        }
+    } else {
+        findings.push(Finding::warning(
+            "endpoint",
+            "not checked",
+            "fix [http] first, the endpoints are reached through it",
+        ));
+    }
+    check_database(config, &mut findings);
+    findings
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::borrow::Cow;
+
+    #[test]
+    fn dirs_and_installed_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("seiran-doctor-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bin"))?;
+        assert_eq!(Severity::Ok, check_dir("install dir", &dir.join("bin")).severity);
+        assert_eq!(Severity::Warning, check_dir("data dir", &dir.join("data")).severity);
+        fs::write(dir.join("file"), "")?;
+        assert_eq!(Severity::Error, check_dir("data dir", &dir.join("file")).severity);
+        let config: Config = toml::from_str(&format!(
+            "api_endpoint = \"http://127.0.0.1:1/\"\nbucket_name = \"bkt\"\ndata_dir = {:?}\ninstall_dir = {:?}",
+            dir.join("data"),
+            dir.join("bin")
+        ))?;
+        fs::write(dir.join("bin/ccc"), "v1")?;
+        let meta = crate::meta::Meta {
+            name: "dir/ccc".into(),
+            md5_hash: check::md5_sum(&fs::File::open(dir.join("bin/ccc"))?)?,
+            ..Default::default()
+        };
+        let mut installed = MetaTable::default();
+        installed.insert(meta);
+        database::save(config.data_dir(), Cow::Owned(installed))?;
+        let mut findings = Vec::new();
+        check_database(&config, &mut findings);
+        assert!(findings.iter().all(|finding| finding.severity == Severity::Ok));
+        fs::write(dir.join("bin/ccc"), "v2")?;
+        let mut findings = Vec::new();
+        check_database(&config, &mut findings);
+        assert!(findings.iter().any(|finding| finding.severity == Severity::Error));
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,33 +1,192 @@
-use crate::meta;
-use colored::Colorize;
+use crate::{
+    bandwidth::Limiter,
+    client::Client,
+    meta::{self, Encoding},
+    mirror::Mirror,
+    perm,
+    report::{Event, Reporter, Step},
+};
 use futures_util::StreamExt;
-use std::{borrow::Cow, fs, io, io::prelude::Write, os::unix::fs::PermissionsExt, path};
+use md5::Digest;
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, prelude::Write},
+    os::unix::fs::PermissionsExt,
+    path,
+};
 
-pub async fn download(target: &meta::Meta, desc: Cow<'_, path::Path>) -> anyhow::Result<fs::File> {
+/// largest window a zstd frame may use, `zstd --long=31`
+const WINDOW_LOG_MAX: u32 = 31;
+
+/// A downloaded object, decoded into the cache.
+pub struct Downloaded {
+    pub file: fs::File,
+    /// md5 of the bytes as stored in the bucket
+    pub md5_hash: String,
+    /// md5 of the decoded content, for compressed objects
+    pub content_md5_hash: Option<String>,
+}
+
+/// Passes writes through, hashing them on the way.
+struct HashWriter<W> {
+    inner: W,
+    hasher: md5::Md5,
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Decoder from the stored encoding to the plain file.
+enum Sink<W: Write> {
+    Identity(W),
+    Gzip(flate2::write::GzDecoder<W>),
+    Zstd(zstd::stream::write::Decoder<'static, W>),
+}
+
+impl<W: Write> Sink<W> {
+    fn new(encoding: Encoding, inner: W) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Identity => Sink::Identity(inner),
+            Encoding::Gzip => Sink::Gzip(flate2::write::GzDecoder::new(inner)),
+            Encoding::Zstd => {
+                let mut decoder = zstd::stream::write::Decoder::new(inner)?;
+                decoder.window_log_max(WINDOW_LOG_MAX)?;
+                Sink::Zstd(decoder)
+            }
+        })
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        match self {
+            Sink::Identity(w) => w.write_all(buf),
+            Sink::Gzip(w) => w.write_all(buf),
+            Sink::Zstd(w) => w.write_all(buf),
+        }
+    }
+
+    fn finish(self) -> io::Result<W> {
+        match self {
+            Sink::Identity(w) => Ok(w),
+            Sink::Gzip(w) => w.finish(),
+            Sink::Zstd(mut w) => {
+                w.flush()?;
+                Ok(w.into_inner())
+            }
+        }
+    }
+}
+
+async fn fetch_to(
+    target: &meta::Meta,
+    mirror: &Mirror<'_>,
+    client: &Client,
+    desc: &path::Path,
+    limiter: &Limiter,
+    reporter: &dyn Reporter,
+) -> anyhow::Result<Downloaded> {
+    log::debug!("GET {}", target.media_link);
+    let mut req = mirror.get(client, &target.media_link);
+    // ask for the stored bytes, GCS would otherwise decompress on the fly and the md5 would not match
+    if let Some(ref encoding) = target.content_encoding {
+        req = req.header(reqwest::header::ACCEPT_ENCODING, encoding.as_str());
+    }
+    let res = req.send().await?;
+    if !res.status().is_success() {
+        return Err(anyhow::Error::msg("Error fetching file!"));
+    }
+    let name = target.name();
+    let mut stream = res.bytes_stream();
+    let file = fs::File::options()
+        .read(true)
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(desc)?;
+    file.set_permissions(fs::Permissions::from_mode(perm::CACHE_MODE))?;
+    let encoding = target.encoding();
+    let content = HashWriter {
+        inner: file.try_clone()?,
+        hasher: md5::Md5::new(),
+    };
+    let mut sink = Sink::new(encoding, content)?;
+    let mut hasher = md5::Md5::new();
+    let mut received = 0u64;
+    let idle_timeout = client.idle_timeout();
+    loop {
+        let bytes = match tokio::time::timeout(idle_timeout, stream.next()).await {
+            Ok(Some(bytes)) => bytes?,
+            Ok(None) => break,
+            Err(_) => {
+                return Err(anyhow::Error::msg(format!(
+                    "Nothing received for {}s.",
+                    idle_timeout.as_secs()
+                )))
+            }
+        };
+        received += bytes.len() as u64;
+        if received > target.size {
+            return Err(anyhow::Error::msg(format!(
+                "Received more than the listed {} bytes!",
+                target.size
+            )));
+        }
+        limiter.consume(bytes.len() as u64).await;
+        hasher.update(&bytes);
+        sink.write_all(&bytes)?;
+        reporter.report(&Event::Progress {
+            name: &name,
+            bytes: bytes.len() as u64,
+        });
+    }
+    if received != target.size {
+        return Err(anyhow::Error::msg(format!(
+            "Received {} bytes, expected {}!",
+            received, target.size
+        )));
+    }
+    let content = sink.finish()?;
+    file.sync_all()?;
+    Ok(Downloaded {
+        file,
+        md5_hash: base64::encode(hasher.finalize()),
+        content_md5_hash: (encoding != Encoding::Identity).then(|| base64::encode(content.hasher.finalize())),
+    })
+}
+
+/// Download `target` into `desc`, decoding compressed objects on the way.
+pub async fn download(
+    target: &meta::Meta,
+    mirror: &Mirror<'_>,
+    client: &Client,
+    desc: Cow<'_, path::Path>,
+    limiter: &Limiter,
+    reporter: &dyn Reporter,
+) -> anyhow::Result<Downloaded> {
     // create cache dir
     fs::create_dir_all(desc.clone()).ok();
     let name = target.name();
-    print!("Downloading {}...", name.cyan());
-    io::stdout().flush().unwrap();
-    let res = reqwest::get(&target.media_link).await?;
-    let desc = desc.join(&name);
-
-    if res.status().is_success() {
-        let mut stream = res.bytes_stream();
-        let mut file = fs::File::with_options()
-            .read(true)
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(desc)?;
-        file.set_permissions(fs::Permissions::from_mode(0o755))?;
-        while let Some(bytes) = stream.next().await {
-            file.write_all(&bytes?)?;
-        }
-        file.sync_all()?;
-        println!("{}", "OK".green());
-        Ok(file)
-    } else {
-        Err(anyhow::Error::msg("Error fetching file!"))
+    let step = Step::Download {
+        name: &name,
+        size: target.size,
+    };
+    reporter.report(&Event::Begin(step));
+    let res = fetch_to(target, mirror, client, &desc.join(&name), limiter, reporter).await;
+    match res {
+        Ok(_) => reporter.report(&Event::Done(step)),
+        Err(ref e) => reporter.report(&Event::Failed {
+            step,
+            error: format!("{:#}", e),
+        }),
     }
+    res
 }
@@ -0,0 +1,1122 @@
+use crate::{
+    bandwidth::Limiter,
+    cache, check, check_free_space, check_md5,
+    client::Client,
+    database, download,
+    history::{self, Action, Record},
+    install,
+    meta::{self, Encoding, Meta, MetaTable},
+    mirror::{self, Mirror, Mirrors},
+    order, patch, perm,
+    report::{self, Event, Reporter, State, Step},
+    rollout, schedule, smoke,
+    store::{Collected, Store},
+    Config, Downloaded,
+};
+use chrono::Utc;
+use futures_util::{stream, StreamExt};
+use once_cell::sync::OnceCell;
+use std::{
+    borrow::Cow,
+    collections::HashSet,
+    fmt, fs, path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+/// downloads running at the same time
+const CONCURRENT_DOWNLOADS: usize = 4;
+
+/// Objects that differ between the bucket and the local database.
+pub struct Plan<'a> {
+    remote: MetaTable,
+    installed: MetaTable,
+    /// updates to install one after the other, a group of objects in the same unit
+    units: Vec<Vec<Meta>>,
+    /// updates this host is not within the rollout of yet
+    deferred: Vec<Meta>,
+    /// updates in a dependency cycle or after one, failed without trying them
+    cycles: Vec<Failure>,
+    /// listings of the mirrors to download from, the reference listing first
+    sources: Vec<(Mirror<'a>, MetaTable)>,
+    /// the reference listing is the primary's, whole enough to tell what left the bucket
+    trusted: bool,
+}
+
+impl Plan<'_> {
+    /// Updates in install order.
+    pub fn delta(&self) -> impl Iterator<Item = &Meta> {
+        self.units.iter().flatten()
+    }
+
+    pub fn deferred(&self) -> &[Meta] {
+        &self.deferred
+    }
+
+    /// Updates no install order fits, which the sync fails.
+    pub fn cycles(&self) -> impl Iterator<Item = &Meta> {
+        self.cycles.iter().map(|failure| &failure.meta)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.units.is_empty() && self.cycles.is_empty()
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.delta().map(|meta| meta.size).sum()
+    }
+}
+
+pub struct Failure {
+    pub meta: Meta,
+    pub error: anyhow::Error,
+}
+
+/// Outcome of [`Seiran::sync`].
+#[derive(Default)]
+pub struct SyncReport {
+    pub installed: Vec<Meta>,
+    /// fetched into the cache, outside every window to install them
+    pub deferred: Vec<Meta>,
+    /// updates held back by their rollout
+    pub rollout: Vec<Meta>,
+    pub failed: Vec<Failure>,
+    /// not tried, the sync stopped at a failure before them
+    pub skipped: Vec<Meta>,
+    /// bytes fetched from the bucket and mirrors, patches included
+    pub downloaded: u64,
+}
+
+impl SyncReport {
+    pub fn is_success(&self) -> bool {
+        self.failed.is_empty()
+    }
+
+    /// Updates not installed after the sync, for whatever reason.
+    pub fn pending(&self) -> usize {
+        self.deferred.len() + self.rollout.len() + self.failed.len() + self.skipped.len()
+    }
+}
+
+/// Error of a sync stopped by a failure without [`Seiran::keep_going`], with what it did before.
+pub struct Aborted {
+    /// the failures that stopped it last
+    pub report: SyncReport,
+}
+
+impl Aborted {
+    fn error(&self) -> &anyhow::Error {
+        &self.report.failed[0].error
+    }
+}
+
+impl fmt::Display for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.error(), f)
+    }
+}
+
+impl fmt::Debug for Aborted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.error(), f)
+    }
+}
+
+impl std::error::Error for Aborted {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error().source()
+    }
+}
+
+/// Objects recorded as installed by previous syncs.
+pub struct Status {
+    pub installed: Vec<Meta>,
+    pub update_at: String,
+}
+
+/// Update engine, for driving seiran from other programs.
+pub struct Seiran<'a> {
+    config: Config<'a>,
+    mirrors: Mirrors<'a>,
+    reporter: Arc<dyn Reporter>,
+    /// built from the `[http]` settings on the first request, unless given
+    client: OnceCell<Client>,
+    keep_going: bool,
+    /// bytes downloaded by the running sync
+    downloaded: AtomicU64,
+    limiter: Limiter,
+}
+
+impl<'a> Seiran<'a> {
+    pub fn new(config: Config<'a>) -> Self {
+        let mirrors = Mirrors::load(config.mirrors(), config.mirror_cooldown(), config.data_dir());
+        let limiter = Limiter::new(config.bandwidth().clone());
+        Self {
+            config,
+            mirrors,
+            reporter: Arc::new(report::Silent),
+            client: OnceCell::new(),
+            keep_going: false,
+            downloaded: AtomicU64::new(0),
+            limiter,
+        }
+    }
+
+    /// Observe the events of every step.
+    pub fn reporter(mut self, reporter: Arc<dyn Reporter>) -> Self {
+        self.reporter = reporter;
+        self
+    }
+
+    /// Send the requests through `client`, shared with other engines, instead of one built from the config.
+    pub fn client(self, client: Client) -> Self {
+        self.client.set(client).ok();
+        self
+    }
+
+    fn http_client(&self) -> anyhow::Result<&Client> {
+        self.client.get_or_try_init(|| Client::new(self.config.http()))
+    }
+
+    /// Continue with the remaining objects when one fails, instead of aborting the sync.
+    pub fn keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    pub fn config(&self) -> &Config<'a> {
+        &self.config
+    }
+
+    /// Fetch the listing of the primary, and of the mirrors too when there is something to download.
+    ///
+    /// Without the primary, the first mirror's listing is the reference, for the objects a quorum of mirrors agrees on.
+    /// Tells whether the reference is the primary's.
+    async fn listings(&self) -> anyhow::Result<(Vec<(Mirror<'a>, MetaTable)>, bool)> {
+        let reporter = self.reporter.as_ref();
+        let client = self.http_client()?;
+        let mut listings = Vec::new();
+        let primary = self.mirrors.primary();
+        let mut last_err = anyhow::Error::msg(format!("{} is in cooldown.", primary.list_api()));
+        if self.mirrors.is_healthy(primary) {
+            match meta::fetch(primary, client, reporter).await {
+                Ok(listing) => {
+                    self.mirrors.mark_ok(primary);
+                    listings.push((primary.clone(), listing.into_owned()));
+                }
+                Err(e) => {
+                    self.mirrors.mark_failed(primary);
+                    last_err = e;
+                }
+            }
+        }
+        if let Some((_, reference)) = listings.first() {
+            let installed = database::load(self.config.data_dir()).unwrap_or_default();
+            if (reference.clone() - installed).is_empty() {
+                return Ok((listings, true));
+            }
+        }
+        let trusted = !listings.is_empty();
+        for mirror in self.mirrors.fallbacks() {
+            match meta::fetch(mirror, client, reporter).await {
+                Ok(listing) => {
+                    self.mirrors.mark_ok(mirror);
+                    listings.push((mirror.clone(), listing.into_owned()));
+                }
+                Err(_) => self.mirrors.mark_failed(mirror),
+            }
+        }
+        if trusted {
+            return Ok((listings, true));
+        }
+        let quorum = self.mirrors.quorum();
+        if listings.len() < quorum {
+            return Err(last_err.context(format!(
+                "{} of the mirrors answered, {} needed to trust them without the primary",
+                listings.len(),
+                quorum
+            )));
+        }
+        let (reference, others) = listings.split_first_mut().expect("a quorum is at least one listing");
+        let others: Vec<_> = others.iter().map(|(_, listing)| listing).collect();
+        mirror::retain_agreed(&mut reference.1, &others, quorum);
+        Ok((listings, false))
+    }
+
+    /// Split `delta` into the updates released to this host and those deferred by their rollout.
+    async fn rollout(
+        &self,
+        delta: Vec<Meta>,
+        sources: &[(Mirror<'a>, MetaTable)],
+    ) -> anyhow::Result<(Vec<Meta>, Vec<Meta>)> {
+        let (mirror, listing) = &sources[0];
+        let manifest = match self.config.rollout_manifest().and_then(|name| listing.get(name)) {
+            Some(meta) => rollout::fetch_manifest(meta, mirror, self.http_client()?).await?,
+            None => Default::default(),
+        };
+        let mut host_id = None;
+        let mut released = Vec::new();
+        let mut deferred = Vec::new();
+        for meta in delta {
+            let percent = match rollout::percent(&meta, &manifest) {
+                Some(percent) => percent,
+                None => {
+                    released.push(meta);
+                    continue;
+                }
+            };
+            let host_id = match host_id {
+                Some(ref host_id) => host_id,
+                None => host_id.insert(rollout::host_id(self.config.host_id())?),
+            };
+            let bucket = rollout::bucket(host_id, &meta.name);
+            log::debug!("{} rolled out to {}%, host at {}", meta.name, percent, bucket);
+            if bucket < percent {
+                released.push(meta);
+            } else {
+                deferred.push(meta);
+            }
+        }
+        Ok((released, deferred))
+    }
+
+    pub async fn plan(&self) -> anyhow::Result<Plan<'a>> {
+        let reporter = self.reporter.as_ref();
+        let sources = self.listings().await;
+        self.mirrors.save()?;
+        let (sources, trusted) = sources?;
+        let mut remote = sources[0].1.clone();
+        let manifest = self.config.rollout_manifest();
+        remote.retain(|meta| {
+            !meta.name.starts_with(self.config.patch_prefix())
+                && Some(&*meta.name) != manifest
+                && self.config.is_wanted(&meta.name)
+        });
+        let installed = database::load(self.config.data_dir()).unwrap_or_default();
+        let (delta, mut deferred) = self.rollout(remote.clone() - installed.clone(), &sources).await?;
+        // a group is released once all of its members are
+        let held: HashSet<_> = deferred
+            .iter()
+            .filter_map(|meta| self.config.object(meta).group)
+            .collect();
+        let (held, delta): (Vec<_>, Vec<_>) = delta.into_iter().partition(|meta| {
+            self.config
+                .object(meta)
+                .group
+                .is_some_and(|group| held.contains(&group))
+        });
+        deferred.extend(held);
+        let sorted = order::sort(delta, self.config.objects());
+        let cycles = sorted.cycles.into_iter().flat_map(|cycle| {
+            let names: Vec<_> = cycle.iter().map(Meta::name).collect();
+            let error = format!("Dependency cycle between {}.", names.join(", "));
+            cycle.into_iter().map(move |meta| Failure {
+                meta,
+                error: anyhow::Error::msg(error.clone()),
+            })
+        });
+        for meta in &deferred {
+            reporter.report(&Event::Status {
+                name: &meta.name(),
+                state: State::DeferredByRollout,
+            });
+        }
+        let plan = Plan {
+            remote,
+            installed,
+            units: sorted.units,
+            deferred,
+            cycles: cycles.collect(),
+            sources,
+            trusted,
+        };
+        reporter.report(&Event::Plan {
+            objects: plan.delta().count(),
+            bytes: plan.bytes(),
+        });
+        Ok(plan)
+    }
+
+    /// Append to the history log, which is not worth failing a sync over.
+    fn record(&self, record: Record) {
+        if let Err(e) = history::append(self.config.data_dir(), &record) {
+            log::warn!("history not recorded: {}", e);
+        }
+    }
+
+    /// Download `meta` into `desc` from the first mirror that agrees with the reference listing and serves it intact.
+    ///
+    /// Unless `optional`, a mirror failing to serve it is put in cooldown.
+    async fn fetch_from(
+        &self,
+        meta: &Meta,
+        sources: &[(Mirror<'a>, MetaTable)],
+        desc: Cow<'_, path::Path>,
+        optional: bool,
+    ) -> anyhow::Result<Downloaded> {
+        let reporter = self.reporter.as_ref();
+        let client = self.http_client()?;
+        let mut res = Err(anyhow::Error::msg("No healthy mirror agrees on the hash."));
+        for (mirror, listing) in sources {
+            // a mirror failing earlier in this sync is in cooldown already
+            if !self.mirrors.is_healthy(mirror) {
+                continue;
+            }
+            let source = match mirror::agrees(meta, listing) {
+                Some(source) => source,
+                None => {
+                    log::warn!("{} disagrees on {}, skipped", mirror.list_api(), meta.name);
+                    continue;
+                }
+            };
+            res = match download(source, mirror, client, desc.clone(), &self.limiter, reporter).await {
+                Ok(downloaded) if check_md5(&downloaded.md5_hash, meta, reporter) => {
+                    self.downloaded.fetch_add(source.size, Ordering::Relaxed);
+                    return Ok(downloaded);
+                }
+                Ok(_) => {
+                    self.record(
+                        Record::new(Action::FailedVerification, None, Some(meta))
+                            .error(format!("md5 mismatch from {}", mirror.list_api())),
+                    );
+                    Err(anyhow::Error::msg("Check_sum failed."))
+                }
+                Err(e) => Err(e),
+            };
+            // a broken patch says little about the mirror, the full download right after would be held back by it
+            if !optional {
+                self.mirrors.mark_failed(mirror);
+            }
+        }
+        res
+    }
+
+    /// Rebuild `meta` from the installed version and a patch object, if the bucket has one.
+    async fn fetch_patched(
+        &self,
+        meta: &Meta,
+        sources: &[(Mirror<'a>, MetaTable)],
+        installed: &Meta,
+    ) -> Option<Downloaded> {
+        let reporter = self.reporter.as_ref();
+        let cache_dir = self.config.cache_dir();
+        let patch_name = patch::patch_name(self.config.patch_prefix(), installed, meta)?;
+        let patch_meta = sources[0].1.get(&patch_name)?;
+        let patch_dir = cache_dir.join(patch::PATCH_DIR);
+        let patch = self
+            .fetch_from(patch_meta, sources, patch_dir.clone().into(), true)
+            .await
+            .ok()?;
+        let name = meta.name();
+        let old = self.config.install_dir().join(installed.install_path().ok()?);
+        let file = report::step(reporter, Step::Patch { name: &name }, || {
+            // the installed file is the reference, it must still be the recorded version
+            if check::md5_sum(&fs::File::open(&old)?)? != installed.content_md5() {
+                return Err(anyhow::Error::msg("Installed file differs from its record."));
+            }
+            patch::apply(&old, &patch.file, &cache_dir.join(&name))
+        });
+        fs::remove_file(patch_dir.join(patch_meta.name())).ok();
+        let file = file.and_then(|file| Ok((check::md5_sum(&file)?, file)));
+        match file {
+            Ok((md5_hash, file)) if check_md5(&md5_hash, meta, reporter) => Some(Downloaded {
+                file,
+                md5_hash,
+                content_md5_hash: None,
+            }),
+            Ok(_) => {
+                self.record(
+                    Record::new(Action::FailedVerification, Some(installed), Some(meta)).error("patched md5 mismatch"),
+                );
+                log::info!("patch for {} unusable, fall back to full download", name);
+                None
+            }
+            _ => {
+                log::info!("patch for {} unusable, fall back to full download", name);
+                None
+            }
+        }
+    }
+
+    /// Reuse the cached file of `meta`, verified by an earlier sync that deferred installing it.
+    fn fetch_staged(&self, meta: &Meta, staged: &MetaTable) -> Option<Downloaded> {
+        let staged = staged.get(&meta.name).filter(|staged| *staged == meta)?;
+        let file = fs::File::open(self.config.cache_dir().join(meta.name())).ok()?;
+        let md5_hash = check::md5_sum(&file).ok()?;
+        if md5_hash != staged.content_md5() {
+            log::info!("cached {} changed since staged, download again", meta.name);
+            return None;
+        }
+        Some(Downloaded {
+            file,
+            md5_hash: meta.md5_hash.clone(),
+            content_md5_hash: staged.content_md5_hash.clone(),
+        })
+    }
+
+    async fn fetch_object(
+        &self,
+        meta: &Meta,
+        sources: &[(Mirror<'a>, MetaTable)],
+        installed: &MetaTable,
+        staged: &MetaTable,
+    ) -> anyhow::Result<Downloaded> {
+        if let Some(file) = self.fetch_staged(meta, staged) {
+            return Ok(file);
+        }
+        // patches are between plain files only
+        let old = installed
+            .get(&meta.name)
+            .filter(|_| meta.encoding() == Encoding::Identity);
+        if let Some(old) = old {
+            if let Some(file) = self.fetch_patched(meta, sources, old).await {
+                return Ok(file);
+            }
+        }
+        self.fetch_from(meta, sources, self.config.cache_dir(), false).await
+    }
+
+    /// Replace the installed file with the cached one, once it passes its smoke test.
+    async fn install(&self, meta: &Meta, old: Option<&Meta>) -> anyhow::Result<()> {
+        let reporter = self.reporter.as_ref();
+        let cache_dir = self.config.cache_dir();
+        let name = meta.name();
+        if let Some(test) = self.config.smoke_test(&name) {
+            smoke::smoke_test(test, &name, &cache_dir.join(&name), reporter).await?;
+        }
+        let permission = self.config.permission(&name);
+        let store = self.config.store();
+        install(
+            meta,
+            old,
+            cache_dir.clone(),
+            self.config.install_dir(),
+            &store,
+            &permission,
+            reporter,
+        )?;
+        // keep the version in the cache as a link to its store entry, so the next download does not overwrite it.
+        // It is installed already, a cache file left under its name is no reason to fail.
+        let entry = Store::entry(meta)?;
+        let (download, kept) = (cache_dir.join(&name), cache_dir.join(&entry));
+        fs::remove_file(&kept).ok();
+        let linked = fs::hard_link(store.dir().join(&entry), &kept).and_then(|()| fs::remove_file(&download));
+        if let Err(e) = linked {
+            // the cache and the store are on different filesystems, the download stays a copy of its own
+            log::debug!("link {} in the cache: {}", entry, e);
+            if let Err(e) = fs::rename(&download, &kept) {
+                log::warn!("keep {} in the cache as {}: {}", name, entry, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Point the file of `meta` back at the version it replaced, or remove it when it is new.
+    fn rollback(&self, meta: &Meta, old: Option<&Meta>) -> anyhow::Result<()> {
+        let install_dir = self.config.install_dir();
+        let name = meta.name();
+        report::step(self.reporter.as_ref(), Step::Rollback { name: &name }, || match old {
+            Some(old) => {
+                let entry = self.config.store().dir().join(Store::entry(old)?);
+                if !entry.exists() {
+                    return Err(anyhow::Error::msg(format!(
+                        "No stored version of {} to roll back to.",
+                        name
+                    )));
+                }
+                let path = old.install_path()?;
+                Store::link(&entry, &install_dir, &path, &self.config.permission(&name))?;
+                // a version published to another path leaves nothing behind
+                if meta.install_path()? != path {
+                    fs::remove_file(install_dir.join(meta.install_path()?))?;
+                }
+                Ok(())
+            }
+            None => Ok(fs::remove_file(install_dir.join(meta.install_path()?))?),
+        })?;
+        self.record(Record::new(Action::Rollback, Some(meta), old));
+        Ok(())
+    }
+
+    /// Unlink the file of `meta`, gone from the bucket or filtered out.
+    fn remove(&self, meta: &Meta) -> anyhow::Result<()> {
+        let name = meta.name();
+        report::step(self.reporter.as_ref(), Step::Remove { name: &name }, || {
+            let path = self.config.install_dir().join(meta.install_path()?);
+            // only a link into the store is ours to remove
+            match fs::read_link(&path) {
+                Ok(target) if target.starts_with(self.config.store_dir()) => Ok(fs::remove_file(&path)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                _ => Err(anyhow::Error::msg(format!(
+                    "{} is not linked by seiran, left in place.",
+                    path.display()
+                ))),
+            }
+        })
+    }
+
+    pub async fn sync(&self, plan: Plan<'a>) -> anyhow::Result<SyncReport> {
+        let reporter = self.reporter.as_ref();
+        let data_dir = self.config.data_dir();
+        let cache_dir = self.config.cache_dir();
+        let install_dir = self.config.install_dir();
+        let Plan {
+            remote,
+            installed,
+            units,
+            deferred,
+            cycles,
+            sources,
+            trusted,
+        } = plan;
+        self.downloaded.store(0, Ordering::Relaxed);
+        let delta: Vec<_> = units.iter().flatten().cloned().collect();
+        if !delta.is_empty() {
+            for dir in [&cache_dir, &data_dir, &install_dir] {
+                perm::check_dir(dir)?;
+            }
+            check_free_space(&delta, &[cache_dir.as_ref(), install_dir.as_ref()], reporter)?;
+        }
+        let mut staged = database::load_staged(data_dir.clone()).unwrap_or_default();
+        let files: Vec<_> = stream::iter(delta.iter())
+            .map(|meta| self.fetch_object(meta, &sources, &installed, &staged))
+            .buffered(CONCURRENT_DOWNLOADS)
+            .collect()
+            .await;
+        self.mirrors.save()?;
+        let mut files = files.into_iter();
+        let mut installed = database::load(data_dir.clone()).unwrap_or_default();
+        let mut res = SyncReport {
+            rollout: deferred,
+            ..Default::default()
+        };
+        // file names left for a later sync and failed, what is installed after them waits or fails too
+        let mut waiting = HashSet::new();
+        let mut failed = HashSet::new();
+        // never tried, so they fail without stopping the sync
+        for failure in cycles {
+            let name = failure.meta.name();
+            reporter.report(&Event::Failed {
+                step: Step::Install { name: &name },
+                error: failure.error.to_string(),
+            });
+            failed.insert(name);
+            res.failed.push(failure);
+        }
+        let mut units = units.into_iter();
+        while let Some(unit) = units.next() {
+            let unit: Vec<_> = unit
+                .into_iter()
+                .zip(files.by_ref())
+                .map(|(mut meta, file)| {
+                    let file = file.map(|downloaded| meta.content_md5_hash = downloaded.content_md5_hash);
+                    (meta, file)
+                })
+                .collect();
+            let after: Vec<_> = unit
+                .iter()
+                .flat_map(|(meta, _)| self.config.object(meta).after)
+                .collect();
+            let failed_after = after.iter().find(|name| failed.contains(*name)).cloned();
+            let closed = !schedule::is_open(self.config.windows(), Utc::now())
+                && unit.iter().any(|(meta, _)| !self.config.is_urgent(&meta.name()));
+            // outside every window, keep the verified files in the cache for the sync that installs them
+            if unit.iter().all(|(_, file)| file.is_ok())
+                && failed_after.is_none()
+                && (closed || after.iter().any(|name| waiting.contains(name)))
+            {
+                let state = if closed {
+                    State::DeferredByWindow
+                } else {
+                    State::DeferredByDependency
+                };
+                for (meta, _) in unit {
+                    let name = meta.name();
+                    reporter.report(&Event::Status { name: &name, state });
+                    staged.insert(meta.clone());
+                    waiting.insert(name);
+                    res.deferred.push(meta);
+                }
+                database::save_staged(data_dir.clone(), Cow::Borrowed(&staged))?;
+                continue;
+            }
+            let mut done = Vec::new();
+            let mut failure = None;
+            let mut unit = unit.into_iter();
+            for (meta, file) in unit.by_ref() {
+                let file = match (file, &failed_after) {
+                    (Err(e), _) => Err(e),
+                    (Ok(()), Some(name)) => Err(anyhow::Error::msg(format!("{} failed, it goes first.", name))),
+                    (Ok(()), None) => self.install(&meta, installed.get(&meta.name)).await,
+                };
+                match file {
+                    Ok(()) => done.push(meta),
+                    Err(error) => {
+                        failure = Some(Failure { meta, error });
+                        break;
+                    }
+                }
+            }
+            let failure = match failure {
+                // record each unit as soon as it is installed, so an aborted sync retries only the rest
+                None => {
+                    for meta in done {
+                        self.record(Record::new(Action::Install, installed.get(&meta.name), Some(&meta)));
+                        installed.insert(meta.clone());
+                        res.installed.push(meta);
+                    }
+                    database::save(data_dir.clone(), Cow::Borrowed(&installed))?;
+                    continue;
+                }
+                Some(failure) => failure,
+            };
+            // a group is installed whole or not at all
+            let name = failure.meta.name();
+            let mut failures = vec![failure];
+            let mut stuck = Vec::new();
+            for meta in done.into_iter().rev() {
+                let error = match self.rollback(&meta, installed.get(&meta.name)) {
+                    Ok(()) => anyhow::Error::msg(format!("{} of its group failed.", name)),
+                    // left installed, the database has to say so
+                    Err(e) => {
+                        stuck.push(meta.clone());
+                        e.context(format!("{} of its group failed, not rolled back", name))
+                    }
+                };
+                failures.push(Failure { meta, error });
+            }
+            if !stuck.is_empty() {
+                for meta in stuck {
+                    self.record(Record::new(Action::Install, installed.get(&meta.name), Some(&meta)));
+                    installed.insert(meta);
+                }
+                database::save(data_dir.clone(), Cow::Borrowed(&installed))?;
+            }
+            failures.extend(unit.map(|(meta, _)| Failure {
+                meta,
+                error: anyhow::Error::msg(format!("{} of its group failed.", name)),
+            }));
+            failed.extend(failures.iter().map(|failure| failure.meta.name()));
+            res.failed.extend(failures);
+            if !self.keep_going {
+                res.skipped = units.flatten().collect();
+                res.downloaded = self.downloaded.load(Ordering::Relaxed);
+                return Err(Aborted { report: res }.into());
+            }
+        }
+        // what the mirrors left out may well be in the bucket still
+        let mut removed = Vec::new();
+        if trusted {
+            for meta in installed.iter().filter(|meta| remote.get(&meta.name).is_none()) {
+                // still linked, it stays recorded and is tried again next sync
+                if self.remove(meta).is_ok() {
+                    removed.push(meta.clone());
+                }
+            }
+            installed.retain(|meta| !removed.contains(meta));
+        }
+        installed.touch();
+        database::save(data_dir.clone(), Cow::Borrowed(&installed))?;
+        let entries = installed
+            .iter()
+            .map(|meta| Ok((meta.name(), Store::entry(meta)?)))
+            .collect::<anyhow::Result<_>>()?;
+        self.config.store().commit(entries)?;
+        for meta in &removed {
+            self.record(Record::new(Action::Removal, Some(meta), None));
+        }
+        if res.is_success() {
+            let collected = self.cache_gc()?;
+            if collected.entries > 0 {
+                reporter.report(&Event::CacheGc {
+                    entries: collected.entries,
+                    bytes: collected.bytes,
+                });
+            }
+        }
+        // drop what got installed or removed from the bucket
+        staged.retain(|meta| {
+            (!trusted || remote.iter().any(|remote| remote == meta)) && !installed.iter().any(|i| i == meta)
+        });
+        database::save_staged(data_dir, Cow::Borrowed(&staged))?;
+        let failed: Vec<_> = res.failed.iter().map(|failure| failure.meta.name()).collect();
+        reporter.report(&Event::Summary {
+            installed: res.installed.len(),
+            failed: &failed,
+        });
+        res.downloaded = self.downloaded.load(Ordering::Relaxed);
+        Ok(res)
+    }
+
+    /// Drop generations beyond the configured count and the store entries only they refer to.
+    pub fn gc(&self, keep: Option<usize>) -> anyhow::Result<Collected> {
+        let store = self.config.store();
+        store.gc(
+            keep.unwrap_or_else(|| self.config.keep_generations()),
+            &self.config.install_dir(),
+        )
+    }
+
+    /// Apply the cache policy, keeping the installed versions and the downloads waiting for a window.
+    pub fn cache_gc(&self) -> anyhow::Result<cache::Collected> {
+        let data_dir = self.config.data_dir();
+        let installed = database::load(data_dir.clone()).unwrap_or_default();
+        let staged = database::load_staged(data_dir).unwrap_or_default();
+        let mut protected = installed
+            .iter()
+            .map(Store::entry)
+            .collect::<anyhow::Result<HashSet<_>>>()?;
+        protected.extend(staged.iter().map(Meta::name));
+        cache::gc(
+            &self.config.cache_dir(),
+            self.config.cache_policy(),
+            &protected,
+            SystemTime::now(),
+        )
+    }
+
+    pub fn status(&self) -> anyhow::Result<Status> {
+        let installed = database::load(self.config.data_dir()).unwrap_or_default();
+        Ok(Status {
+            update_at: installed.update_at().to_owned(),
+            installed: installed.iter().cloned().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::order::{AFTER_KEY, GROUP_KEY};
+    use hyper::{
+        header,
+        service::{make_service_fn, service_fn},
+        Body, Request, Response, Server, StatusCode,
+    };
+    use md5::Digest;
+    use std::{
+        collections::{BTreeMap, HashMap},
+        convert::Infallible,
+        net::SocketAddr,
+        os::unix::fs::MetadataExt,
+        sync::{atomic::AtomicUsize, Mutex},
+    };
+
+    /// content and custom metadata of an object
+    type Object = (Vec<u8>, HashMap<String, String>);
+
+    /// Objects served the way the bucket API lists and downloads them.
+    #[derive(Default)]
+    struct Bucket {
+        /// by object name
+        objects: Mutex<BTreeMap<String, Object>>,
+        downloads: AtomicUsize,
+    }
+
+    impl Bucket {
+        fn put(&self, name: &str, content: &str, metadata: &[(&str, &str)]) {
+            let metadata = metadata
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(name.into(), (content.into(), metadata));
+        }
+
+        fn remove(&self, name: &str) {
+            self.objects.lock().unwrap().remove(name);
+        }
+
+        fn respond(&self, req: &Request<Body>) -> Response<Body> {
+            let host = req
+                .headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or_default();
+            let objects = self.objects.lock().unwrap();
+            let path = req.uri().path();
+            if path.ends_with("/o") {
+                let items: Vec<_> = objects
+                    .iter()
+                    .map(|(name, (content, metadata))| {
+                        let md5_hash = base64::encode(md5::Md5::digest(content));
+                        Meta {
+                            name: name.clone(),
+                            media_link: format!("http://{}/download/{}", host, name),
+                            id: format!("bkt/{}/{}", name, md5_hash),
+                            md5_hash,
+                            size: content.len() as u64,
+                            metadata: metadata.clone(),
+                            ..Default::default()
+                        }
+                    })
+                    .collect();
+                return Response::new(serde_json::json!({ "items": items }).to_string().into());
+            }
+            match path.strip_prefix("/download/").and_then(|name| objects.get(name)) {
+                Some((content, _)) => {
+                    self.downloads.fetch_add(1, Ordering::Relaxed);
+                    Response::new(content.clone().into())
+                }
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap(),
+            }
+        }
+
+        /// Serve on a local port until the runtime is dropped, as `http://<addr>/`.
+        fn serve(self: &Arc<Self>, rt: &tokio::runtime::Runtime) -> anyhow::Result<String> {
+            let _guard = rt.enter();
+            let bucket = self.clone();
+            let make_svc = make_service_fn(move |_| {
+                let bucket = bucket.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let res = bucket.respond(&req);
+                        async move { Ok::<_, Infallible>(res) }
+                    }))
+                }
+            });
+            let server = Server::try_bind(&"127.0.0.1:0".parse()?)?.serve(make_svc);
+            let addr: SocketAddr = server.local_addr();
+            rt.spawn(server);
+            Ok(format!("http://{}/", addr))
+        }
+    }
+
+    /// A config syncing from `endpoint` into `dir`, with `extra` lines at the top level.
+    fn config(dir: &path::Path, endpoint: &str, extra: &str) -> anyhow::Result<Config<'static>> {
+        let file = dir.join("config.toml");
+        fs::create_dir_all(dir)?;
+        fs::write(
+            &file,
+            format!(
+                "api_endpoint = {:?}\nbucket_name = \"bkt\"\ncache_dir = {:?}\ndata_dir = {:?}\ninstall_dir = {:?}\n{}",
+                endpoint,
+                dir.join("cache"),
+                dir.join("data"),
+                dir.join("bin"),
+                extra
+            ),
+        )?;
+        Config::from_file(&file)
+    }
+
+    fn sync(rt: &tokio::runtime::Runtime, config: &Config<'static>, keep_going: bool) -> anyhow::Result<SyncReport> {
+        let seiran = Seiran::new(config.clone()).keep_going(keep_going);
+        rt.block_on(async { seiran.sync(seiran.plan().await?).await })
+    }
+
+    fn names<'m>(metas: impl IntoIterator<Item = &'m Meta>) -> Vec<String> {
+        metas.into_iter().map(Meta::name).collect()
+    }
+
+    fn actions(config: &Config<'_>, action: &str) -> anyhow::Result<Vec<String>> {
+        Ok(history::read(config.data_dir(), None)?
+            .into_iter()
+            .filter(|record| record.action.as_str() == action)
+            .map(|record| record.name)
+            .collect())
+    }
+
+    /// A script passing the smoke test or not.
+    fn script(pass: bool, version: u32) -> String {
+        format!("#!/bin/sh\n# {}\nexit {}\n", version, if pass { 0 } else { 1 })
+    }
+
+    #[test]
+    fn removal_unlinks() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-engine-removal-{}", std::process::id()));
+        let bucket = Arc::new(Bucket::default());
+        bucket.put("tools/aaa", "aaa", &[]);
+        bucket.put("tools/bbb", "bbb", &[]);
+        let endpoint = bucket.serve(&rt)?;
+        let bin = dir.join("bin");
+        assert_eq!(
+            vec!["aaa", "bbb"],
+            names(&sync(&rt, &config(&dir, &endpoint, "")?, false)?.installed)
+        );
+        // filtered out, then gone from the bucket
+        let filtered = config(&dir, &endpoint, "exclude = [\"bbb\"]")?;
+        sync(&rt, &filtered, false)?;
+        assert!(bin.join("aaa").exists() && !bin.join("bbb").exists());
+        bucket.remove("tools/aaa");
+        sync(&rt, &filtered, false)?;
+        assert!(!bin.join("aaa").exists());
+        assert_eq!(vec!["bbb", "aaa"], actions(&filtered, "removal")?);
+        let generations = filtered.store().generations()?;
+        assert!(generations.last().is_some_and(|last| last.entries.is_empty()));
+        // a file seiran did not link stays, and so does its record
+        bucket.put("tools/ccc", "v1", &[]);
+        sync(&rt, &filtered, false)?;
+        fs::remove_file(bin.join("ccc"))?;
+        fs::write(bin.join("ccc"), "mine")?;
+        bucket.remove("tools/ccc");
+        sync(&rt, &filtered, false)?;
+        assert_eq!(b"mine", &fs::read(bin.join("ccc"))?[..]);
+        assert_eq!(vec!["ccc"], names(&Seiran::new(filtered).status()?.installed));
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn mirrors_without_primary_remove_nothing() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-engine-quorum-{}", std::process::id()));
+        let (primary, full, partial) = (
+            Arc::new(Bucket::default()),
+            Arc::new(Bucket::default()),
+            Arc::new(Bucket::default()),
+        );
+        for bucket in [&primary, &full, &partial] {
+            bucket.put("tools/aaa", "aaa", &[]);
+        }
+        for bucket in [&primary, &full] {
+            bucket.put("tools/bbb", "bbb", &[]);
+        }
+        let mirrors = format!(
+            "[[mirrors]]\napi_endpoint = {:?}\nbucket_name = \"bkt\"\n[[mirrors]]\napi_endpoint = {:?}\nbucket_name = \"bkt\"",
+            full.serve(&rt)?,
+            partial.serve(&rt)?
+        );
+        sync(&rt, &config(&dir, &primary.serve(&rt)?, &mirrors)?, false)?;
+        // the mirrors agree on aaa alone, bbb may well be in the bucket still
+        let down = config(&dir, "http://127.0.0.1:1/", &mirrors)?;
+        sync(&rt, &down, false)?;
+        assert_eq!(
+            vec!["aaa", "bbb"],
+            names(&Seiran::new(down.clone()).status()?.installed)
+        );
+        assert!(dir.join("bin/bbb").exists());
+        assert!(actions(&down, "removal")?.is_empty());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn group_rolls_back() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-engine-group-{}", std::process::id()));
+        let bucket = Arc::new(Bucket::default());
+        bucket.put("tools/aaa", &script(true, 1), &[(GROUP_KEY, "g")]);
+        bucket.put("tools/bbb", &script(true, 1), &[(GROUP_KEY, "g")]);
+        let config = config(&dir, &bucket.serve(&rt)?, "[smoke_tests.bbb]\nargs = []")?;
+        sync(&rt, &config, false)?;
+        let generations = config.store().generations()?.len();
+        // bbb fails its smoke test after aaa is installed, aaa goes back to the version before
+        bucket.put("tools/aaa", &script(true, 2), &[(GROUP_KEY, "g")]);
+        bucket.put("tools/bbb", &script(false, 2), &[(GROUP_KEY, "g")]);
+        let report = sync(&rt, &config, true)?;
+        assert!(report.installed.is_empty());
+        assert_eq!(
+            vec!["bbb", "aaa"],
+            names(report.failed.iter().map(|failure| &failure.meta))
+        );
+        assert_eq!(script(true, 1), fs::read_to_string(dir.join("bin/aaa"))?);
+        assert_eq!(vec!["aaa"], actions(&config, "rollback")?);
+        // nothing changed, no generation either
+        assert_eq!(generations, config.store().generations()?.len());
+        let installed = Seiran::new(config.clone()).status()?.installed;
+        assert!(installed.iter().all(|meta| meta
+            .id
+            .ends_with(&base64::encode(md5::Md5::digest(script(true, 1).as_bytes())))));
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn keep_going_or_abort() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-engine-abort-{}", std::process::id()));
+        let bucket = Arc::new(Bucket::default());
+        bucket.put("tools/aaa", &script(true, 1), &[]);
+        bucket.put("tools/bbb", &script(false, 1), &[]);
+        bucket.put("tools/ccc", &script(true, 1), &[]);
+        let config = config(&dir, &bucket.serve(&rt)?, "[smoke_tests.bbb]\nargs = []")?;
+        let error = match sync(&rt, &config, false) {
+            Ok(_) => panic!("bbb fails its smoke test"),
+            Err(error) => error,
+        };
+        let report = &error.downcast_ref::<Aborted>().expect("an aborted sync").report;
+        assert_eq!(vec!["aaa"], names(&report.installed));
+        assert_eq!(vec!["bbb"], names(report.failed.iter().map(|failure| &failure.meta)));
+        assert_eq!(vec!["ccc"], names(&report.skipped));
+        assert_eq!(2, report.pending());
+        // what got installed before the failure is recorded, and linked in a generation
+        assert_eq!(vec!["aaa"], names(&Seiran::new(config.clone()).status()?.installed));
+        let report = sync(&rt, &config, true)?;
+        assert_eq!(vec!["ccc"], names(&report.installed));
+        assert_eq!(vec!["bbb"], names(report.failed.iter().map(|failure| &failure.meta)));
+        let generations = config.store().generations()?;
+        let last = generations.last().expect("a generation");
+        assert_eq!(vec!["aaa", "ccc"], last.entries.keys().collect::<Vec<_>>());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn cycle_fails_alone() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-engine-cycle-{}", std::process::id()));
+        let bucket = Arc::new(Bucket::default());
+        bucket.put("tools/aaa", "aaa", &[(AFTER_KEY, "bbb")]);
+        bucket.put("tools/bbb", "bbb", &[(AFTER_KEY, "aaa")]);
+        bucket.put("tools/ccc", "ccc", &[]);
+        let config = config(&dir, &bucket.serve(&rt)?, "")?;
+        let report = sync(&rt, &config, false)?;
+        assert_eq!(vec!["ccc"], names(&report.installed));
+        let failed: Vec<_> = report.failed.iter().map(|failure| &failure.meta).collect();
+        assert_eq!(vec!["aaa", "bbb"], names(failed));
+        assert_eq!("Dependency cycle between aaa, bbb.", report.failed[0].error.to_string());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    #[test]
+    fn window_defers_and_reuses_staged() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-engine-window-{}", std::process::id()));
+        let bucket = Arc::new(Bucket::default());
+        bucket.put("tools/aaa", "aaa", &[]);
+        let endpoint = bucket.serve(&rt)?;
+        // a window two days away is closed now, whatever the time of day
+        let day = chrono::Datelike::weekday(&Utc::now()).succ().succ();
+        let closed = config(
+            &dir,
+            &endpoint,
+            &format!(
+                "[[windows]]\ndays = [\"{}\"]\nstart = \"00:00\"\nend = \"00:01\"\ntimezone = \"UTC\"",
+                day
+            ),
+        )?;
+        let report = sync(&rt, &closed, false)?;
+        assert!(report.installed.is_empty());
+        assert_eq!(vec!["aaa"], names(&report.deferred));
+        assert!(!dir.join("bin/aaa").exists());
+        assert_eq!(1, bucket.downloads.load(Ordering::Relaxed));
+        // the window opens, the file verified then is installed without downloading it again
+        let open = config(&dir, &endpoint, "")?;
+        assert_eq!(vec!["aaa"], names(&sync(&rt, &open, false)?.installed));
+        assert_eq!(1, bucket.downloads.load(Ordering::Relaxed));
+        assert_eq!("aaa", fs::read_to_string(dir.join("bin/aaa"))?);
+        assert!(database::load_staged(open.data_dir())?.iter().next().is_none());
+        // the cache keeps the installed version as the store entry itself
+        let installed = Seiran::new(open.clone()).status()?.installed;
+        let entry = Store::entry(&installed[0])?;
+        let inode = |path: path::PathBuf| Ok::<_, std::io::Error>(fs::metadata(path)?.ino());
+        assert_eq!(
+            inode(open.store_dir().join(&entry))?,
+            inode(open.cache_dir().join(&entry))?
+        );
+        assert!(!open.cache_dir().join("aaa").exists());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
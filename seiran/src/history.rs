@@ -0,0 +1,148 @@
+use crate::meta::Meta;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fs,
+    io::{self, BufRead, Write},
+    path,
+};
+
+const HISTORY: &str = "history.jsonl";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    Install,
+    /// the object left the bucket or the filters, its file is unlinked and its record dropped
+    Removal,
+    /// an install undone for a failure elsewhere
+    Rollback,
+    /// a download or patch did not match its listed md5
+    FailedVerification,
+}
+
+impl Action {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Action::Install => "install",
+            Action::Removal => "removal",
+            Action::Rollback => "rollback",
+            Action::FailedVerification => "failed verification",
+        }
+    }
+}
+
+/// One line of the history log.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Record {
+    pub time: String,
+    pub action: Action,
+    /// file name the object is installed as
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_id: Option<String>,
+    /// content md5 of the version replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_md5: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_md5: Option<String>,
+    /// `seiran-version` of the version replaced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Record {
+    /// `action` moving from `old` to `new`, either may be missing.
+    pub fn new(action: Action, old: Option<&Meta>, new: Option<&Meta>) -> Self {
+        Self {
+            time: chrono::offset::Local::now().to_rfc3339(),
+            action,
+            name: old.or(new).map(Meta::name).unwrap_or_default(),
+            old_id: old.map(|meta| meta.id.clone()),
+            old_md5: old.map(|meta| meta.content_md5().to_owned()),
+            new_id: new.map(|meta| meta.id.clone()),
+            new_md5: new.map(|meta| meta.content_md5().to_owned()),
+            old_version: old.and_then(Meta::version).map(ToOwned::to_owned),
+            new_version: new.and_then(Meta::version).map(ToOwned::to_owned),
+            error: None,
+        }
+    }
+
+    pub fn error(mut self, error: impl ToString) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+/// Append `record` to the log in `data_dir`, the log is never rewritten.
+pub fn append(data_dir: Cow<'_, path::Path>, record: &Record) -> Result<()> {
+    fs::create_dir_all(&data_dir).ok();
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    // one write per line, so concurrent appends do not interleave
+    let mut file = fs::File::options()
+        .append(true)
+        .create(true)
+        .open(data_dir.join(HISTORY))?;
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// Records of `name`, or every record, oldest first.
+pub fn read(data_dir: Cow<'_, path::Path>, name: Option<&str>) -> Result<Vec<Record>> {
+    let file = match fs::File::open(data_dir.join(HISTORY)) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    for line in io::BufReader::new(file).lines() {
+        let line = line?;
+        // a line cut short by a crash is skipped, not fatal
+        match serde_json::from_str::<Record>(&line) {
+            Ok(record) if name.is_none_or(|name| name == record.name) => records.push(record),
+            Ok(_) => {}
+            Err(e) => log::warn!("skip history line {:?}: {}", line, e),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn append_and_filter() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("seiran-history-{}", std::process::id()));
+        let meta = |name: &str, id: &str| Meta {
+            name: name.into(),
+            id: id.into(),
+            md5_hash: "aaa".into(),
+            ..Default::default()
+        };
+        let (old, new) = (meta("dir/ccc", "1"), meta("dir/ccc", "2"));
+        append(
+            dir.as_path().into(),
+            &Record::new(Action::Install, Some(&old), Some(&new)),
+        )?;
+        append(
+            dir.as_path().into(),
+            &Record::new(Action::FailedVerification, None, Some(&meta("dir/ddd", "1"))).error("md5 mismatch"),
+        )?;
+        let records = read(dir.as_path().into(), Some("ccc"))?;
+        assert_eq!(1, records.len());
+        assert_eq!(Some("1"), records[0].old_id.as_deref());
+        assert_eq!(Some("2"), records[0].new_id.as_deref());
+        assert_eq!(2, read(dir.as_path().into(), None)?.len());
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -1,17 +1,44 @@
-use crate::meta;
-use colored::Colorize;
-use std::{borrow::Cow, fs, io, io::Write, path};
+use crate::{
+    meta,
+    perm::Permission,
+    report::{self, Reporter, Step},
+    store::Store,
+};
+use std::{borrow::Cow, fs, io, path};
 
+/// Install `meta` from the cache, in place of the `old` version when there is one.
 pub fn install<'a>(
     meta: &meta::Meta,
+    old: Option<&meta::Meta>,
     cache_dir: Cow<'a, path::Path>,
     install_dir: Cow<'a, path::Path>,
+    store: &Store<'_>,
+    permission: &Permission,
+    reporter: &dyn Reporter,
 ) -> anyhow::Result<()> {
-    print!("Install {}...", meta.name().cyan());
-    io::stdout().flush().unwrap();
-    let from = cache_dir.join(meta.name());
-    let to = install_dir.join(meta.name());
-    fs::copy(from, to)?;
-    println!("{}", "OK".green());
-    Ok(())
+    let name = meta.name();
+    report::step(reporter, Step::Install { name: &name }, || {
+        let path = meta.install_path()?;
+        // the publisher's mode wins, the owner stays the one configured
+        let permission = match meta.mode()? {
+            Some(mode) => Cow::Owned(permission.with_mode(mode)),
+            None => Cow::Borrowed(permission),
+        };
+        let entry = store.add(&cache_dir.join(&name), meta, &permission)?;
+        log::debug!("link {} to {}", install_dir.join(&path).display(), entry.display());
+        Store::link(&entry, &install_dir, &path, &permission)?;
+        // the old version may have been published to another path, its link would stay forever
+        if let Some(old) = old
+            .map(meta::Meta::install_path)
+            .transpose()?
+            .filter(|old| *old != path)
+        {
+            log::debug!("remove {}", install_dir.join(&old).display());
+            match fs::remove_file(install_dir.join(&old)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    })
 }
@@ -0,0 +1,245 @@
+use crate::report::{self, Reporter, Step};
+use anyhow::Result;
+use std::{collections::BTreeMap, env, fmt, fs, path};
+use toml::{value::Table, Value};
+
+/// system wide config, with drop-ins in `config.d` next to it
+pub const SYSTEM_CONFIG: &str = "/etc/seiran/config.toml";
+/// prefix of environment variables overriding config keys, `__` separates nested keys
+const ENV_PREFIX: &str = "SEIRAN_";
+/// last segments of the keys `seiran config show` does not print the values of,
+/// webhook URLs and proxies carry their credentials in them
+const SECRETS: &[&str] = &["token", "client_key", "url", "proxy"];
+
+/// Where a config value comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File(path::PathBuf),
+    Env(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => f.write_str("default"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Env(var) => write!(f, "environment {}", var),
+        }
+    }
+}
+
+/// Config files and environment merged in order, remembering which layer set each key.
+///
+/// Tables merge key by key, any other value, arrays included, replaces the one below.
+pub struct Layers {
+    table: Table,
+    /// dotted key -> layer that last set it
+    origins: BTreeMap<String, Origin>,
+    /// dotted key -> variable value as given, for keys set by the environment
+    env: BTreeMap<String, String>,
+}
+
+fn segments(key: &str) -> impl Iterator<Item = &str> {
+    key.split(['.', '[', ']']).filter(|segment| !segment.is_empty())
+}
+
+/// Whether the value of `key` is a credential, the path to one, or a URL with one.
+pub fn is_secret(key: &str) -> bool {
+    segments(key).last().is_some_and(|segment| SECRETS.contains(&segment))
+}
+
+impl Layers {
+    pub fn new(defaults: Table) -> Self {
+        let mut layers = Self {
+            table: Table::new(),
+            origins: BTreeMap::new(),
+            env: BTreeMap::new(),
+        };
+        layers.merge(defaults, &Origin::Default);
+        layers
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    pub fn merge(&mut self, table: Table, origin: &Origin) {
+        fn merge_into(
+            into: &mut Table,
+            table: Table,
+            prefix: &str,
+            origin: &Origin,
+            origins: &mut BTreeMap<String, Origin>,
+        ) {
+            for (key, value) in table {
+                let dotted = format!("{}{}", prefix, key);
+                match (into.get_mut(&key), value) {
+                    (Some(Value::Table(into)), Value::Table(table)) => {
+                        merge_into(into, table, &format!("{}.", dotted), origin, origins)
+                    }
+                    (_, value) => {
+                        // a replaced table takes the keys below it along
+                        origins.retain(|key, _| !key.starts_with(&format!("{}.", dotted)));
+                        origins.insert(dotted, origin.clone());
+                        into.insert(key, value);
+                    }
+                }
+            }
+        }
+        merge_into(&mut self.table, table, "", origin, &mut self.origins);
+    }
+
+    pub fn merge_file(&mut self, path: &path::Path) -> Result<()> {
+        let content = fs::read_to_string(path)?;
+        let table = toml::from_str(&content).map_err(|e| anyhow::Error::msg(format!("{}: {}", path.display(), e)))?;
+        self.merge(table, &Origin::File(path.to_owned()));
+        Ok(())
+    }
+
+    /// Merge `SEIRAN_*` variables, values parsed as TOML when they are valid TOML and taken as strings otherwise.
+    pub fn merge_env(&mut self, vars: impl Iterator<Item = (String, String)>) {
+        for (var, raw) in vars {
+            let key = match var.strip_prefix(ENV_PREFIX) {
+                Some(key) if !key.is_empty() => key.to_lowercase(),
+                _ => continue,
+            };
+            let value = toml::from_str::<Table>(&format!("value = {}", raw))
+                .ok()
+                .and_then(|mut table| table.remove("value"))
+                .unwrap_or_else(|| Value::String(raw.clone()));
+            self.env.insert(key.replace("__", "."), raw);
+            let value = key.rsplit("__").fold(value, |value, key| {
+                Value::Table(Table::from_iter([(key.to_owned(), value)]))
+            });
+            if let Value::Table(table) = value {
+                self.merge(table, &Origin::Env(var));
+            }
+        }
+    }
+
+    /// Put back the variable `key` was set from as a string in `table`, when it was parsed as another type.
+    /// Returns whether anything changed, so a failed deserialization is worth another try.
+    pub fn stringify_env(&self, table: &mut Table, key: &str) -> bool {
+        let raw = match self.env.get(key) {
+            Some(raw) => raw,
+            None => return false,
+        };
+        let mut segments: Vec<_> = segments(key).collect();
+        let last = match segments.pop() {
+            Some(last) => last,
+            None => return false,
+        };
+        let table = segments
+            .into_iter()
+            .try_fold(table, |table, segment| match table.get_mut(segment) {
+                Some(Value::Table(table)) => Some(table),
+                _ => None,
+            });
+        match table.and_then(|table| table.get_mut(last)) {
+            Some(value) if !value.is_str() => {
+                *value = Value::String(raw.clone());
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Layer that set `key`, given dotted like `source[1].name`.
+    pub fn origin(&self, key: &str) -> &Origin {
+        let segments: Vec<_> = segments(key).collect();
+        (1..=segments.len())
+            .rev()
+            .find_map(|len| self.origins.get(&segments[..len].join(".")))
+            .unwrap_or(&Origin::Default)
+    }
+
+    /// Every value with its dotted key, tables and arrays of tables flattened.
+    pub fn settings(&self) -> Vec<(String, &Value)> {
+        fn flatten<'t>(table: &'t Table, prefix: &str, settings: &mut Vec<(String, &'t Value)>) {
+            for (key, value) in table {
+                let dotted = format!("{}{}", prefix, key);
+                match value {
+                    Value::Table(table) => flatten(table, &format!("{}.", dotted), settings),
+                    Value::Array(array) if array.iter().all(Value::is_table) && !array.is_empty() => {
+                        for (i, table) in array.iter().filter_map(Value::as_table).enumerate() {
+                            flatten(table, &format!("{}[{}].", dotted, i), settings);
+                        }
+                    }
+                    value => settings.push((dotted, value)),
+                }
+            }
+        }
+        let mut settings = Vec::new();
+        flatten(&self.table, "", &mut settings);
+        settings
+    }
+
+    /// The system config, its drop-ins in name order, then `user` and the environment.
+    pub fn load(defaults: Table, user: &path::Path, explicit: bool, reporter: &dyn Reporter) -> Result<Self> {
+        let mut layers = Self::new(defaults);
+        let system = path::Path::new(SYSTEM_CONFIG);
+        let mut files = vec![system.to_owned()];
+        if let Ok(dir) = fs::read_dir(system.with_file_name("config.d")) {
+            let mut drop_ins: Vec<_> = dir
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect();
+            drop_ins.sort();
+            files.extend(drop_ins);
+        }
+        files.push(user.to_owned());
+        for path in files {
+            // only a config given explicitly must exist
+            if !(path.exists() || explicit && path == user) {
+                continue;
+            }
+            report::step(reporter, Step::LoadConfig { path: &path }, || layers.merge_file(&path))?;
+        }
+        layers.merge_env(env::vars());
+        Ok(layers)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn later_layers_win() -> anyhow::Result<()> {
+        let mut layers = Layers::new(toml::from_str("mirror_cooldown = 600")?);
+        let system = Origin::File("/etc/seiran/config.toml".into());
+        let drop_in = Origin::File("/etc/seiran/config.d/10-smoke.toml".into());
+        layers.merge(
+            toml::from_str("bucket_name = \"a\"\n[smoke_tests.hello]\nargs = []")?,
+            &system,
+        );
+        layers.merge(toml::from_str("[smoke_tests.hello]\ntimeout = 3")?, &drop_in);
+        layers.merge_env(
+            [
+                ("SEIRAN_BUCKET_NAME".to_owned(), "b".to_owned()),
+                ("SEIRAN_SMOKE_TESTS__HELLO__TIMEOUT".to_owned(), "5".to_owned()),
+                ("OTHER".to_owned(), "c".to_owned()),
+            ]
+            .into_iter(),
+        );
+        assert_eq!(Some("b"), layers.table()["bucket_name"].as_str());
+        assert_eq!(Some(5), layers.table()["smoke_tests"]["hello"]["timeout"].as_integer());
+        assert_eq!(&Origin::Default, layers.origin("mirror_cooldown"));
+        assert_eq!(&system, layers.origin("smoke_tests.hello.args[0]"));
+        assert_eq!(
+            &Origin::Env("SEIRAN_SMOKE_TESTS__HELLO__TIMEOUT".into()),
+            layers.origin("smoke_tests.hello.timeout")
+        );
+        assert_eq!(4, layers.settings().len());
+        layers.merge_env([("SEIRAN_HOST_ID".to_owned(), "1234".to_owned())].into_iter());
+        let mut table = layers.table().clone();
+        assert!(layers.stringify_env(&mut table, "host_id"));
+        assert_eq!(Some("1234"), table["host_id"].as_str());
+        assert!(!layers.stringify_env(&mut table, "host_id"));
+        assert!(!layers.stringify_env(&mut table, "mirror_cooldown"));
+        assert!(is_secret("source[1].token") && is_secret("http.client_key") && !is_secret("bucket_name"));
+        assert!(is_secret("webhooks[0].url") && is_secret("http.proxy") && !is_secret("http.no_proxy"));
+        Ok(())
+    }
+}
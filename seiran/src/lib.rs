@@ -1,14 +1,35 @@
-#![feature(bool_to_option, with_options)]
+pub mod bandwidth;
+pub mod cache;
 mod check;
+pub mod client;
 mod config;
 pub mod database;
+pub mod doctor;
 mod download;
+mod engine;
+pub mod history;
 mod install;
+pub mod layers;
 pub mod meta;
+pub mod metrics;
+pub mod mirror;
+pub mod order;
+mod patch;
+pub mod perm;
+mod progress;
+pub mod report;
+mod rollout;
+mod schedule;
+mod serve;
+pub mod smoke;
+pub mod store;
+pub mod webhook;
 
 const APPLICATION: &str = "seiran";
 
-pub use check::check_md5_sum;
+pub use check::{check_free_space, check_md5, check_md5_sum};
 pub use config::Config;
-pub use download::download;
+pub use download::{download, Downloaded};
+pub use engine::{Aborted, Failure, Plan, Seiran, Status, SyncReport};
 pub use install::install;
+pub use serve::serve;
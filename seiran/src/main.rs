@@ -1,58 +1,363 @@
-use colored::Colorize;
-use seiran::{check_md5_sum, database, download, install, meta, Config};
-use std::{
-    env::args,
-    io::{self, Write},
-    path::PathBuf,
+use clap::{ArgEnum, Parser, Subcommand};
+use seiran::{
+    client::Client,
+    doctor::Finding,
+    layers,
+    meta::Meta,
+    metrics::{self, Metrics},
+    report::{self, Event, Reporter, Severity, State},
+    webhook::{self, Notification},
+    Aborted, Config, Seiran,
 };
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
-fn failed(e: anyhow::Error) -> anyhow::Error {
-    println!("{}\n", "Failed".red());
-    e
-}
-
-async fn run(config: Config<'static>) -> anyhow::Result<()> {
-    let data_dir = config.data_dir();
-    let cache_dir = config.cache_dir();
-    let install_dir = config.install_dir();
-    println!(
-        "{}\ndata dir: {}\ncache dir: {}\ninstall dir: {}",
-        "::<> Check config.".blue(),
-        data_dir.to_string_lossy().cyan(),
-        cache_dir.to_string_lossy().cyan(),
-        install_dir.to_string_lossy().cyan()
-    );
-    println!("{}", "::<> Seiran.".blue());
-    let prev = database::load(data_dir.clone()).unwrap_or_default();
-    let uri = config.list_api();
-    let data = meta::fetch(uri.as_ref()).await.map_err(failed)?;
-    let delta = data.clone().into_owned() - prev;
-    if delta.is_empty() {
-        println!("{}", "No update.".green());
-    }
-    for meta in delta.iter() {
-        let file = download(meta, cache_dir.clone()).await.map_err(failed)?;
-        if !check_md5_sum(file, meta).map_err(failed)? {
-            println!("{}", "Exited".red());
-            return Err(anyhow::Error::msg("Check_sum failed."));
-        }
-        install(meta, cache_dir.clone(), install_dir.clone()).map_err(failed)?;
-    }
-    database::save(data_dir.clone(), data)?;
+#[derive(ArgEnum, Clone, Copy)]
+enum Output {
+    Human,
+    Json,
+}
+
+#[derive(Parser)]
+#[clap(version = clap::crate_version!(), author = clap::crate_authors!())]
+struct Opts {
+    /// config file, default to XDG_CONFIG_HOME/seiran/config.toml
+    config: Option<PathBuf>,
+    /// continue with the remaining objects when one fails
+    #[clap(short, long = "keep-going")]
+    keep_going: bool,
+    /// output format
+    #[clap(short, long, arg_enum, default_value = "human", global = true)]
+    output: Output,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// serve the installed objects to other hosts, in the listing format seiran consumes
+    Serve {
+        /// address to listen on
+        #[clap(short, long, default_value = "0.0.0.0:8080")]
+        listen: SocketAddr,
+        /// `[[source]]` to serve, default to the first
+        #[clap(short, long)]
+        source: Option<String>,
+    },
+    /// show installed objects and pending updates, including those deferred by rollout
+    Status,
+    /// remove store entries no generation to keep refers to
+    Gc {
+        /// generations to keep, default to keep_generations in config
+        #[clap(short, long)]
+        keep: Option<usize>,
+    },
+    /// show installs, removals and failed verifications, oldest first
+    History {
+        /// only the object installed under this file name
+        name: Option<String>,
+    },
+    /// inspect and collect the download cache
+    Cache {
+        #[clap(subcommand)]
+        command: CacheCommand,
+    },
+    /// check the config, dirs, endpoints, credentials, database and clock, and suggest fixes
+    Doctor,
+    /// inspect the merged config
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+}
+
+#[derive(Subcommand, Clone, Copy)]
+enum CacheCommand {
+    /// list cached files, oldest first
+    Ls,
+    /// remove cached files beyond the `[cache]` policy
+    Gc,
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// print every effective value and the file or variable it comes from
+    Show,
+}
+
+/// Names of `metas`, prefixed with the source in configs with several.
+fn names(config: &Config, metas: impl Iterator<Item = impl std::borrow::Borrow<Meta>>) -> Vec<String> {
+    metas
+        .map(|meta| match config.name() {
+            Some(source) => format!("{}:{}", source, meta.borrow().name()),
+            None => meta.borrow().name(),
+        })
+        .collect()
+}
+
+/// Metrics are for alerting, failing to write them does not fail the run.
+fn write_metrics(config: &Config<'_>, samples: &[Metrics], now: i64) {
+    if let Some(path) = config.metrics_file() {
+        if let Err(e) = metrics::write(path, samples, now) {
+            log::warn!("write metrics to {}: {}", path.display(), e);
+        }
+    }
+}
+
+fn report_finding(reporter: &dyn Reporter, finding: &Finding) {
+    reporter.report(&Event::Finding {
+        check: &finding.check,
+        severity: finding.severity,
+        detail: &finding.detail,
+        hint: finding.hint.as_deref(),
+    });
+}
+
+async fn doctor(config: anyhow::Result<Config<'_>>, reporter: &dyn Reporter) -> anyhow::Result<()> {
+    let mut findings = Vec::new();
+    let config = match config {
+        Ok(config) => config,
+        Err(e) => {
+            let finding = Finding::error("config", format!("{:#}", e), "fix the file or variable named above");
+            report_finding(reporter, &finding);
+            return Err(anyhow::Error::msg("1 problem found."));
+        }
+    };
+    let client = Client::new(config.http());
+    findings.push(match client {
+        Ok(_) => Finding::ok("config", "loaded"),
+        Err(ref e) => Finding::error("config", format!("[http]: {:#}", e), "fix the paths in [http]"),
+    });
+    findings.iter().for_each(|finding| report_finding(reporter, finding));
+    for config in config.sources() {
+        if let Some(name) = config.name() {
+            reporter.report(&Event::Source { name });
+        }
+        let found = seiran::doctor::doctor(&config, client.as_ref().ok()).await;
+        found.iter().for_each(|finding| report_finding(reporter, finding));
+        findings.extend(found);
+    }
+    let problems = findings
+        .iter()
+        .filter(|finding| finding.severity == Severity::Error)
+        .count();
+    if problems > 0 {
+        return Err(anyhow::Error::msg(format!("{} problem(s) found.", problems)));
+    }
+    Ok(())
+}
+
+async fn run(config: Config<'_>, keep_going: bool, reporter: Arc<dyn Reporter>) -> anyhow::Result<()> {
+    let mut installed = 0;
+    let mut failed = Vec::new();
+    let mut failed_sources = Vec::new();
+    let mut samples = Vec::new();
+    let now = chrono::Utc::now().timestamp();
+    // built once for every source, the [http] settings are global
+    let client = Client::new(config.http())?;
+    let sources = config.sources();
+    let combined = sources.len() > 1;
+    for config in sources {
+        if let Some(name) = config.name() {
+            reporter.report(&Event::Source { name });
+        }
+        reporter.report(&Event::Config {
+            data_dir: &config.data_dir(),
+            cache_dir: &config.cache_dir(),
+            install_dir: &config.install_dir(),
+        });
+        let seiran = Seiran::new(config.clone())
+            .client(client.clone())
+            .reporter(reporter.clone())
+            .keep_going(keep_going);
+        let res = match seiran.plan().await {
+            Ok(plan) => seiran.sync(plan).await,
+            Err(e) => Err(e),
+        };
+        let notification = match res {
+            Ok(ref res) => Notification::new(config.name(), res),
+            Err(ref e) => Notification::failure(config.name(), e),
+        };
+        if !notification.is_empty() {
+            if let Err(e) = webhook::notify(&client, config.webhooks(), &notification).await {
+                log::warn!("notify: {}", e);
+            }
+        }
+        // a sync stopped by a failure still tells what it did before
+        let report = match res {
+            Ok(ref res) => Some(res),
+            Err(ref e) => e.downcast_ref::<Aborted>().map(|aborted| &aborted.report),
+        };
+        match Metrics::new(&config, report, now) {
+            Ok(metrics) => {
+                if metrics.success {
+                    if let Err(e) = metrics::save_success(&config, now) {
+                        log::warn!("save last success: {}", e);
+                    }
+                }
+                samples.push(metrics);
+            }
+            Err(e) => log::warn!("collect metrics: {}", e),
+        }
+        match res {
+            Ok(res) => {
+                installed += res.installed.len();
+                failed.extend(names(&config, res.failed.iter().map(|failure| &failure.meta)));
+            }
+            // a broken source does not hold back the others
+            Err(e) if keep_going && config.name().is_some() => {
+                log::warn!("{}: {}", config.name().unwrap_or_default(), e);
+                failed_sources.extend(config.name().map(ToOwned::to_owned));
+            }
+            Err(e) => {
+                write_metrics(&config, &samples, now);
+                return Err(e);
+            }
+        }
+    }
+    write_metrics(&config, &samples, now);
+    // each sync reports its own summary, the total is only worth it over several
+    if combined {
+        reporter.report(&Event::Total {
+            installed,
+            failed: &failed,
+            failed_sources: &failed_sources,
+        });
+    }
+    match (failed.len(), failed_sources.len()) {
+        (0, 0) => Ok(()),
+        (objects, 0) => Err(anyhow::Error::msg(format!("{} object(s) failed to sync.", objects))),
+        (0, sources) => Err(anyhow::Error::msg(format!("{} source(s) failed to sync.", sources))),
+        (objects, sources) => Err(anyhow::Error::msg(format!(
+            "{} object(s) and {} source(s) failed to sync.",
+            objects, sources
+        ))),
+    }
+}
+
+async fn status(config: Config<'_>, reporter: &dyn Reporter) -> anyhow::Result<()> {
+    let client = Client::new(config.http())?;
+    for config in config.sources() {
+        if let Some(name) = config.name() {
+            reporter.report(&Event::Source { name });
+        }
+        // the listing is only looked at, the plan events would read like a sync
+        let seiran = Seiran::new(config.clone()).client(client.clone());
+        let plan = seiran.plan().await?;
+        let pending = names(&config, plan.delta().chain(plan.cycles()));
+        let deferred = names(&config, plan.deferred().iter());
+        let installed = names(&config, seiran.status()?.installed.into_iter());
+        let installed = installed
+            .into_iter()
+            .filter(|name| !pending.contains(name) && !deferred.contains(name));
+        let states = installed
+            .map(|name| (name, State::Installed))
+            .chain(pending.iter().map(|name| (name.clone(), State::Pending)))
+            .chain(deferred.iter().map(|name| (name.clone(), State::DeferredByRollout)));
+        for (name, state) in states {
+            reporter.report(&Event::Status { name: &name, state });
+        }
+    }
     Ok(())
 }
 
 fn main() -> anyhow::Result<()> {
-    let path = if let Some(path) = args().nth(1) {
-        PathBuf::from(path)
-    } else {
-        Config::default_config_path()
+    env_logger::init();
+    let Opts {
+        config,
+        keep_going,
+        output,
+        command,
+    } = Opts::parse();
+    let reporter: Arc<dyn Reporter> = match output {
+        Output::Human => Arc::new(report::Human::new()),
+        Output::Json => Arc::new(report::Json),
     };
-    print!("Load config from {}...", path.to_string_lossy().cyan());
-    io::stdout().flush().unwrap();
-    let config = Config::from_file(&path).map_err(failed)?;
-    println!("{}", "OK".green());
+    let loaded = Config::load(config.as_deref(), reporter.as_ref());
     let rt = tokio::runtime::Runtime::new()?;
-    rt.block_on(run(config))?;
+    match command {
+        Some(Command::Serve { listen, source }) => {
+            let (config, _) = loaded?;
+            let sources = config.sources();
+            let config = match source {
+                Some(source) => sources.iter().find(|config| config.name() == Some(&source)),
+                None => sources.first(),
+            }
+            .ok_or_else(|| anyhow::Error::msg("No such source."))?;
+            rt.block_on(seiran::serve(config, listen, reporter.as_ref()))?
+        }
+        Some(Command::Config {
+            command: ConfigCommand::Show,
+        }) => {
+            let (_, layers) = loaded?;
+            for (key, value) in layers.settings() {
+                let value = if layers::is_secret(&key) {
+                    "\"<redacted>\"".to_owned()
+                } else {
+                    value.to_string()
+                };
+                reporter.report(&Event::Setting {
+                    key: &key,
+                    value: &value,
+                    origin: &layers.origin(&key).to_string(),
+                });
+            }
+        }
+        Some(Command::Gc { keep }) => {
+            let (config, _) = loaded?;
+            for config in config.sources() {
+                if let Some(name) = config.name() {
+                    reporter.report(&Event::Source { name });
+                }
+                let collected = Seiran::new(config).gc(keep)?;
+                reporter.report(&Event::Gc {
+                    generations: collected.generations,
+                    entries: collected.entries,
+                    bytes: collected.bytes,
+                });
+            }
+        }
+        Some(Command::Cache { command }) => {
+            let (config, _) = loaded?;
+            for config in config.sources() {
+                if let Some(name) = config.name() {
+                    reporter.report(&Event::Source { name });
+                }
+                match command {
+                    CacheCommand::Ls => {
+                        for entry in seiran::cache::ls(&config.cache_dir())? {
+                            reporter.report(&Event::CacheEntry {
+                                name: &entry.name,
+                                version: entry.version.as_deref(),
+                                bytes: entry.bytes,
+                                modified: &chrono::DateTime::<chrono::Local>::from(entry.modified).to_rfc3339(),
+                            });
+                        }
+                    }
+                    CacheCommand::Gc => {
+                        let collected = Seiran::new(config).cache_gc()?;
+                        reporter.report(&Event::CacheGc {
+                            entries: collected.entries,
+                            bytes: collected.bytes,
+                        });
+                    }
+                }
+            }
+        }
+        Some(Command::History { name }) => {
+            let (config, _) = loaded?;
+            for config in config.sources() {
+                if let Some(name) = config.name() {
+                    reporter.report(&Event::Source { name });
+                }
+                for record in seiran::history::read(config.data_dir(), name.as_deref())? {
+                    reporter.report(&Event::History { record: &record });
+                }
+            }
+        }
+        Some(Command::Status) => rt.block_on(status(loaded?.0, reporter.as_ref()))?,
+        // diagnoses the config too, which may be what is broken
+        Some(Command::Doctor) => rt.block_on(doctor(loaded.map(|(config, _)| config), reporter.as_ref()))?,
+        None => rt.block_on(run(loaded?.0, keep_going, reporter))?,
+    }
     Ok(())
 }
@@ -1,7 +1,30 @@
+use crate::{
+    client::Client,
+    mirror::Mirror,
+    report::{Event, Reporter, Step},
+};
 use anyhow::Result;
-use colored::Colorize;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{borrow::Cow, cmp, fmt::Display, io, io::Write, ops::Sub, str::FromStr};
+use std::{
+    borrow::Cow,
+    cmp,
+    collections::HashMap,
+    fmt::Display,
+    ops::Sub,
+    path::{self, Component},
+    str::FromStr,
+};
+
+/// custom metadata key of the path to install an object at, relative to the install dir
+pub const INSTALL_PATH_KEY: &str = "seiran-install-path";
+/// custom metadata key of the octal mode of the installed file, over the configured one
+pub const MODE_KEY: &str = "seiran-mode";
+/// custom metadata key of the version shown in the history and notifications
+pub const VERSION_KEY: &str = "seiran-version";
+/// custom metadata key of the decoded size of a compressed object
+pub const CONTENT_SIZE_KEY: &str = "seiran-content-size";
+/// decoded size assumed per stored byte of a compressed object without `seiran-content-size`
+const EXPANSION: u64 = 4;
 
 fn deserialize_number_from_string<'de, T, D>(deserializer: D) -> Result<T, D::Error>
 where
@@ -22,7 +45,34 @@ where
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+fn deserialize_optional_number_from_string<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr + serde::Deserialize<'de>,
+    <T as FromStr>::Err: Display,
+{
+    deserialize_number_from_string(deserializer).map(Some)
+}
+
+/// How an object is stored in the bucket.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    fn suffix(self) -> &'static str {
+        match self {
+            Encoding::Identity => "",
+            Encoding::Gzip => ".gz",
+            Encoding::Zstd => ".zst",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 #[serde(rename_all = "camelCase")]
 pub struct Meta {
     pub name: String,
@@ -30,12 +80,121 @@ pub struct Meta {
     pub id: String,
     pub md5_hash: String,
     #[serde(deserialize_with = "deserialize_number_from_string")]
-    pub size: u32,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_encoding: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
+    /// md5 of the decoded content, recorded locally for compressed objects
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_md5_hash: Option<String>,
+    /// custom metadata set on the object
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+    /// RFC 3339 time the object was last changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated: Option<String>,
+    /// generation of the object data, changed by every overwrite
+    #[serde(
+        default,
+        deserialize_with = "deserialize_optional_number_from_string",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub generation: Option<u64>,
+    /// base64 big-endian CRC32C of the stored bytes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub crc32c: Option<String>,
 }
 
 impl Meta {
+    pub fn encoding(&self) -> Encoding {
+        match (self.content_encoding.as_deref(), self.content_type.as_deref()) {
+            (Some("gzip"), _) | (_, Some("application/gzip" | "application/x-gzip")) => Encoding::Gzip,
+            (Some("zstd"), _) | (_, Some("application/zstd")) => Encoding::Zstd,
+            _ if self.name.ends_with(Encoding::Gzip.suffix()) => Encoding::Gzip,
+            _ if self.name.ends_with(Encoding::Zstd.suffix()) => Encoding::Zstd,
+            _ => Encoding::Identity,
+        }
+    }
+
+    /// Full name of the decoded content, without the compression suffix.
+    pub fn content_name(&self) -> &str {
+        let suffix = self.encoding().suffix();
+        self.name.strip_suffix(suffix).unwrap_or(&self.name)
+    }
+
+    /// File name the object is cached as, and installed as unless `seiran-install-path` says otherwise.
     pub fn name(&self) -> String {
-        self.name.split('/').last().unwrap_or_default().to_owned()
+        self.content_name()
+            .split('/')
+            .next_back()
+            .unwrap_or_default()
+            .to_owned()
+    }
+
+    fn custom_install_path(&self) -> Result<Option<path::PathBuf>> {
+        let path = match self.metadata.get(INSTALL_PATH_KEY) {
+            Some(path) => path::PathBuf::from(path),
+            None => return Ok(None),
+        };
+        // publishers place files under the install dir, never elsewhere
+        if path.file_name().is_none() || !path.components().all(|part| matches!(part, Component::Normal(_))) {
+            return Err(anyhow::Error::msg(format!(
+                "{} {} leaves the install dir.",
+                INSTALL_PATH_KEY,
+                path.display()
+            )));
+        }
+        // nor over the store or the temporary links, all dot names
+        if path.iter().any(|part| part.to_string_lossy().starts_with('.')) {
+            return Err(anyhow::Error::msg(format!(
+                "{} {} has a hidden part, kept for seiran.",
+                INSTALL_PATH_KEY,
+                path.display()
+            )));
+        }
+        Ok(Some(path))
+    }
+
+    /// Where the object is installed, relative to the install dir.
+    pub fn install_path(&self) -> Result<path::PathBuf> {
+        Ok(self.custom_install_path()?.unwrap_or_else(|| self.name().into()))
+    }
+
+    /// Mode set by `seiran-mode`, permission bits only.
+    pub fn mode(&self) -> Result<Option<u32>> {
+        let mode = match self.metadata.get(MODE_KEY) {
+            Some(mode) => mode,
+            None => return Ok(None),
+        };
+        match u32::from_str_radix(mode.trim_start_matches("0o"), 8) {
+            Ok(mode) if mode <= 0o777 => Ok(Some(mode)),
+            _ => Err(anyhow::Error::msg(format!(
+                "{} {} is not an octal mode up to 777.",
+                MODE_KEY, mode
+            ))),
+        }
+    }
+
+    pub fn version(&self) -> Option<&str> {
+        self.metadata.get(VERSION_KEY).map(String::as_str)
+    }
+
+    /// Bytes of the decoded content, estimated for compressed objects that do not tell.
+    pub fn content_size(&self) -> u64 {
+        match self.encoding() {
+            Encoding::Identity => self.size,
+            _ => self
+                .metadata
+                .get(CONTENT_SIZE_KEY)
+                .and_then(|size| size.parse().ok())
+                .unwrap_or_else(|| self.size.saturating_mul(EXPANSION)),
+        }
+    }
+
+    /// md5 of the content as installed.
+    pub fn content_md5(&self) -> &str {
+        self.content_md5_hash.as_deref().unwrap_or(&self.md5_hash)
     }
 }
 
@@ -50,13 +209,18 @@ impl cmp::Eq for Meta {}
 #[derive(Deserialize)]
 struct ListObjects {
     items: Vec<Meta>,
+    /// set in the database, missing from a bucket listing
+    #[serde(default)]
+    update_at: Option<String>,
 }
 
 impl From<ListObjects> for MetaTable {
     fn from(input: ListObjects) -> MetaTable {
         Self {
             items: input.items,
-            update_at: chrono::offset::Local::now().to_rfc3339(),
+            update_at: input
+                .update_at
+                .unwrap_or_else(|| chrono::offset::Local::now().to_rfc3339()),
         }
     }
 }
@@ -68,6 +232,42 @@ pub struct MetaTable {
     update_at: String,
 }
 
+impl MetaTable {
+    pub fn iter(&self) -> impl Iterator<Item = &Meta> {
+        self.items.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Meta> {
+        self.items.iter().find(|meta| meta.name == name)
+    }
+
+    pub fn retain(&mut self, f: impl FnMut(&Meta) -> bool) {
+        self.items.retain(f);
+    }
+
+    pub fn update_at(&self) -> &str {
+        &self.update_at
+    }
+
+    /// Mark the table as synced now.
+    pub fn touch(&mut self) {
+        self.update_at = chrono::offset::Local::now().to_rfc3339();
+    }
+
+    /// Record `meta` as installed, replacing any previous object with the same name.
+    pub fn insert(&mut self, meta: Meta) {
+        self.items.retain(|item| item.name != meta.name);
+        self.items.push(meta);
+        self.update_at = chrono::offset::Local::now().to_rfc3339();
+    }
+
+    /// Drop records of objects that no longer exist in `remote`.
+    pub fn retain_in(&mut self, remote: &MetaTable) {
+        self.items
+            .retain(|item| remote.items.iter().any(|meta| meta.name == item.name));
+    }
+}
+
 impl Default for MetaTable {
     fn default() -> Self {
         Self {
@@ -77,6 +277,15 @@ impl Default for MetaTable {
     }
 }
 
+impl FromIterator<Meta> for MetaTable {
+    fn from_iter<I: IntoIterator<Item = Meta>>(items: I) -> Self {
+        Self {
+            items: items.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
 impl Sub for MetaTable {
     type Output = Vec<Meta>;
 
@@ -88,12 +297,32 @@ impl Sub for MetaTable {
     }
 }
 
-pub async fn fetch<'a>(uri: &str) -> Result<Cow<'a, MetaTable>> {
-    print!("Fetch meta...");
-    io::stdout().flush().unwrap();
-    let res = reqwest::get(uri).await?.json().await?;
-    println!("{}", "OK".green());
-    Ok(res)
+pub async fn fetch<'a>(mirror: &Mirror<'_>, client: &Client, reporter: &dyn Reporter) -> Result<Cow<'a, MetaTable>> {
+    let uri = mirror.list_api();
+    let step = Step::FetchMeta { uri: &uri };
+    reporter.report(&Event::Begin(step));
+    log::debug!("GET {}", uri);
+    let res: Result<Cow<'a, MetaTable>> =
+        // the listing is small, the read timeout covers all of it
+        async {
+            Ok(mirror
+                .get(client, &uri)
+                .timeout(client.idle_timeout())
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?)
+        }
+        .await;
+    match res {
+        Ok(_) => reporter.report(&Event::Done(step)),
+        Err(ref e) => reporter.report(&Event::Failed {
+            step,
+            error: format!("{:#}", e),
+        }),
+    }
+    res
 }
 
 #[cfg(test)]
@@ -108,6 +337,7 @@ mod test {
             media_link: "aaa".into(),
             md5_hash: "aaa".into(),
             size: 3333,
+            ..Default::default()
         };
         let meta2 = Meta {
             name: "ccc2".into(),
@@ -115,11 +345,108 @@ mod test {
             media_link: "aaa".into(),
             md5_hash: "aaa".into(),
             size: 3333,
+            ..Default::default()
         };
-        let table1: MetaTable = ListObjects { items: vec![meta1] }.into();
-        let table2: MetaTable = ListObjects { items: vec![meta2] }.into();
+        let table1: MetaTable = ListObjects {
+            items: vec![meta1],
+            update_at: None,
+        }
+        .into();
+        let table2: MetaTable = ListObjects {
+            items: vec![meta2],
+            update_at: None,
+        }
+        .into();
         let sub = table1 - table2;
         assert_eq!("1", sub.first().unwrap().id);
         Ok(())
     }
+
+    #[test]
+    fn compressed_name() {
+        let meta = |name: &str, content_encoding: Option<&str>| Meta {
+            name: name.into(),
+            content_encoding: content_encoding.map(Into::into),
+            ..Default::default()
+        };
+        assert_eq!("ccc", meta("dir/ccc.zst", None).name());
+        assert_eq!("ccc", meta("dir/ccc.gz", Some("gzip")).name());
+        assert_eq!("ccc.zst", meta("dir/ccc.zst", Some("gzip")).name());
+        assert_eq!(Encoding::Gzip, meta("dir/ccc", Some("gzip")).encoding());
+        let mut compressed = Meta {
+            size: 10,
+            ..meta("dir/ccc.zst", None)
+        };
+        assert_eq!(40, compressed.content_size());
+        compressed.metadata.insert(CONTENT_SIZE_KEY.to_owned(), "25".to_owned());
+        assert_eq!(25, compressed.content_size());
+        assert_eq!("ccc", meta("dir/ccc", Some("gzip")).name());
+    }
+
+    #[test]
+    fn size_over_4gib() -> anyhow::Result<()> {
+        let meta: Meta =
+            serde_json::from_str(r#"{"name":"a","mediaLink":"b","id":"c","md5Hash":"d","size":"8589934592"}"#)?;
+        assert_eq!(8 << 30, meta.size);
+        Ok(())
+    }
+
+    #[test]
+    fn insert_replaces_by_name() {
+        let meta = |id: &str| Meta {
+            name: "dir/ccc".into(),
+            id: id.into(),
+            media_link: "aaa".into(),
+            md5_hash: "aaa".into(),
+            size: 3333,
+            ..Default::default()
+        };
+        let mut table = MetaTable::default();
+        table.insert(meta("1"));
+        table.insert(meta("2"));
+        assert_eq!(vec!["2"], table.iter().map(|m| m.id.as_str()).collect::<Vec<_>>());
+        table.retain_in(&MetaTable::default());
+        assert_eq!(0, table.iter().count());
+    }
+
+    #[test]
+    fn update_at_persists() -> anyhow::Result<()> {
+        let table: MetaTable = serde_json::from_str(r#"{"items": [], "update_at": "2021-10-04T08:00:00+00:00"}"#)?;
+        assert_eq!("2021-10-04T08:00:00+00:00", table.update_at());
+        let table: MetaTable = serde_json::from_str(&serde_json::to_string(&table)?)?;
+        assert_eq!("2021-10-04T08:00:00+00:00", table.update_at());
+        Ok(())
+    }
+
+    #[test]
+    fn custom_metadata() -> anyhow::Result<()> {
+        let meta: Meta = serde_json::from_str(
+            r#"{
+                "name": "dir/ccc", "id": "bkt/dir/ccc/1", "mediaLink": "aaa", "md5Hash": "aaa", "size": "3",
+                "updated": "2021-10-04T08:00:00.000Z", "generation": "1633334400000000", "crc32c": "AAAAAA==",
+                "metadata": {"seiran-install-path": "sbin/ccc-1", "seiran-mode": "0750", "seiran-version": "1.2.0"}
+            }"#,
+        )?;
+        assert_eq!(Some(1633334400000000), meta.generation);
+        assert_eq!("ccc", meta.name());
+        assert_eq!(path::Path::new("sbin/ccc-1"), meta.install_path()?);
+        assert_eq!(Some(0o750), meta.mode()?);
+        assert_eq!(Some("1.2.0"), meta.version());
+        let with = |key: &str, value: &str| Meta {
+            name: "dir/ccc".into(),
+            metadata: HashMap::from_iter([(key.to_owned(), value.to_owned())]),
+            ..Default::default()
+        };
+        assert!(with(INSTALL_PATH_KEY, "../etc/passwd").install_path().is_err());
+        assert!(with(INSTALL_PATH_KEY, "/etc/passwd").install_path().is_err());
+        assert!(with(INSTALL_PATH_KEY, ".seiran/0cc175b9c0f1b6a831c399e269772661-ccc")
+            .install_path()
+            .is_err());
+        assert!(with(INSTALL_PATH_KEY, "bin/.ccc.seiran").install_path().is_err());
+        assert!(with(INSTALL_PATH_KEY, ".config/ccc").install_path().is_err());
+        assert_eq!("ccc", with(INSTALL_PATH_KEY, "/etc/passwd").name());
+        assert!(with(MODE_KEY, "4755").mode().is_err());
+        assert_eq!(path::Path::new("ccc"), with(MODE_KEY, "0644").install_path()?);
+        Ok(())
+    }
 }
@@ -0,0 +1,196 @@
+use crate::{engine::SyncReport, Config};
+use anyhow::Result;
+use std::{borrow::Cow, collections::BTreeMap, fmt::Write, fs, path};
+
+/// unix time of the last run without failures, kept across runs in the data dir
+const LAST_SUCCESS: &str = "last_success";
+
+/// State of one source after a run.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    /// name of the source, unlabelled without `[[source]]`
+    pub source: Option<String>,
+    pub success: bool,
+    /// `None` until a run succeeds
+    pub last_success: Option<i64>,
+    /// `None` when the run failed before planning
+    pub pending: Option<usize>,
+    pub failed: usize,
+    pub downloaded: u64,
+    /// installed file name -> generation it was installed in
+    pub installed_in: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    /// Metrics of a run of `config` at `now`, `report` is `None` when it failed before syncing.
+    pub fn new(config: &Config<'_>, report: Option<&SyncReport>, now: i64) -> Result<Self> {
+        let success = report.is_some_and(SyncReport::is_success);
+        let last_success = if success {
+            Some(now)
+        } else {
+            last_success(config.data_dir())
+        };
+        Ok(Self {
+            source: config.name().map(ToOwned::to_owned),
+            success,
+            last_success,
+            pending: report.map(SyncReport::pending),
+            failed: report.map_or(0, |report| report.failed.len()),
+            downloaded: report.map_or(0, |report| report.downloaded),
+            installed_in: config.store().installed_in()?,
+        })
+    }
+}
+
+/// Remember a run of `config` at `now` without failures, for the runs after it.
+pub fn save_success(config: &Config<'_>, now: i64) -> Result<()> {
+    let data_dir = config.data_dir();
+    fs::create_dir_all(&data_dir)?;
+    fs::write(data_dir.join(LAST_SUCCESS), now.to_string())?;
+    Ok(())
+}
+
+fn last_success(data_dir: Cow<'_, path::Path>) -> Option<i64> {
+    fs::read_to_string(data_dir.join(LAST_SUCCESS))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// One metric family in the Prometheus text format.
+struct Family<'m> {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<(Vec<(&'static str, &'m str)>, f64)>,
+}
+
+impl<'m> Family<'m> {
+    fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            samples: Vec::new(),
+        }
+    }
+
+    fn sample(&mut self, metrics: &'m Metrics, value: impl Into<f64>) -> &mut Self {
+        self.labelled(metrics, Vec::new(), value)
+    }
+
+    fn labelled(
+        &mut self,
+        metrics: &'m Metrics,
+        mut labels: Vec<(&'static str, &'m str)>,
+        value: impl Into<f64>,
+    ) -> &mut Self {
+        if let Some(ref source) = metrics.source {
+            labels.insert(0, ("source", source));
+        }
+        self.samples.push((labels, value.into()));
+        self
+    }
+
+    fn write(&self, out: &mut String) -> std::fmt::Result {
+        writeln!(out, "# HELP {} {}", self.name, self.help)?;
+        writeln!(out, "# TYPE {} gauge", self.name)?;
+        for (labels, value) in &self.samples {
+            let labels: Vec<_> = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                .collect();
+            if labels.is_empty() {
+                writeln!(out, "{} {}", self.name, value)?;
+            } else {
+                writeln!(out, "{}{{{}}} {}", self.name, labels.join(","), value)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `metrics` of every source in the Prometheus text format.
+pub fn render(metrics: &[Metrics], now: i64) -> String {
+    let mut families = [
+        Family::new("seiran_last_run_timestamp_seconds", "Unix time of the last run."),
+        Family::new("seiran_last_run_success", "Whether the last run synced every object."),
+        Family::new(
+            "seiran_last_success_timestamp_seconds",
+            "Unix time of the last run that synced every object.",
+        ),
+        Family::new("seiran_pending_updates", "Updates not installed after the last run."),
+        Family::new("seiran_failed_objects", "Objects that failed to sync in the last run."),
+        Family::new("seiran_downloaded_bytes", "Bytes downloaded in the last run."),
+        Family::new(
+            "seiran_installed_generation",
+            "Store generation the installed file came with.",
+        ),
+    ];
+    for metrics in metrics {
+        let [last_run, success, last_success, pending, failed, downloaded, installed_in] = &mut families;
+        last_run.sample(metrics, now as f64);
+        success.sample(metrics, u8::from(metrics.success));
+        if let Some(time) = metrics.last_success {
+            last_success.sample(metrics, time as f64);
+        }
+        if let Some(count) = metrics.pending {
+            pending.sample(metrics, count as f64);
+        }
+        failed.sample(metrics, metrics.failed as f64);
+        downloaded.sample(metrics, metrics.downloaded as f64);
+        for (name, generation) in &metrics.installed_in {
+            installed_in.labelled(metrics, vec![("name", name)], *generation as f64);
+        }
+    }
+    let mut out = String::new();
+    for family in families.iter().filter(|family| !family.samples.is_empty()) {
+        family.write(&mut out).expect("write to a string");
+    }
+    out
+}
+
+/// Replace `path` with `metrics` in one rename, the collector never reads a partial file.
+pub fn write(path: &path::Path, metrics: &[Metrics], now: i64) -> Result<()> {
+    // next to the target, so the rename stays on one filesystem; the collector skips names not ending in .prom
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, render(metrics, now))?;
+    fs::File::open(&tmp)?.sync_all()?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_text_format() {
+        let metrics = [
+            Metrics {
+                source: Some("tools".into()),
+                success: true,
+                last_success: Some(100),
+                pending: Some(0),
+                installed_in: BTreeMap::from_iter([("ccc".to_owned(), 2)]),
+                ..Default::default()
+            },
+            Metrics {
+                source: Some("a\"b".into()),
+                failed: 1,
+                ..Default::default()
+            },
+        ];
+        let text = render(&metrics, 200);
+        assert!(text.contains("# TYPE seiran_last_success_timestamp_seconds gauge\n"));
+        assert!(text.contains("seiran_last_success_timestamp_seconds{source=\"tools\"} 100\n"));
+        assert!(!text.contains("seiran_last_success_timestamp_seconds{source=\"a\\\"b\"}"));
+        assert!(text.contains("seiran_failed_objects{source=\"a\\\"b\"} 1\n"));
+        assert!(text.contains("seiran_installed_generation{source=\"tools\",name=\"ccc\"} 2\n"));
+        let unnamed = render(&[Metrics::default()], 200);
+        assert!(unnamed.contains("seiran_last_run_timestamp_seconds 200\n"));
+    }
+}
@@ -0,0 +1,174 @@
+use crate::{
+    client::Client,
+    meta::{Meta, MetaTable},
+};
+use anyhow::Result;
+use serde::Deserialize;
+use std::{borrow::Cow, collections::HashMap, fs, path, sync::Mutex};
+
+const HEALTH: &str = "mirrors.json";
+
+/// One place to fetch the listing and objects from.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Mirror<'a> {
+    /// https://storage.googleapis.com/storage/v1/
+    pub api_endpoint: Cow<'a, str>,
+    /// example_bucket
+    pub bucket_name: Cow<'a, str>,
+    /// OAuth2 bearer token, for private buckets
+    #[serde(default)]
+    pub token: Option<Cow<'a, str>>,
+}
+
+impl<'a> Mirror<'a> {
+    pub fn list_api(&self) -> Cow<'a, str> {
+        self.api_endpoint.clone() + "b/" + self.bucket_name.clone() + "/o"
+    }
+
+    /// GET `url` through `client` with the credentials of this mirror.
+    pub fn get(&self, client: &Client, url: &str) -> reqwest::RequestBuilder {
+        let req = client.get(url);
+        match self.token {
+            Some(ref token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+}
+
+/// Ordered mirrors, with failures remembered across runs so a broken mirror is skipped for a while.
+pub struct Mirrors<'a> {
+    mirrors: Vec<Mirror<'a>>,
+    /// list api -> unix time of the last failure
+    failed_at: Mutex<HashMap<String, i64>>,
+    cooldown: i64,
+    data_dir: Cow<'a, path::Path>,
+}
+
+impl<'a> Mirrors<'a> {
+    pub fn load(mirrors: Vec<Mirror<'a>>, cooldown: u64, data_dir: Cow<'a, path::Path>) -> Self {
+        let failed_at = fs::File::open(data_dir.join(HEALTH))
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default();
+        Self {
+            mirrors,
+            failed_at: Mutex::new(failed_at),
+            cooldown: cooldown as i64,
+            data_dir,
+        }
+    }
+
+    pub fn save(&self) -> Result<()> {
+        fs::create_dir_all(&self.data_dir).ok();
+        let path = self.data_dir.join(HEALTH);
+        // write aside then rename, like the database
+        let tmp = path.with_extension("json.tmp");
+        let file = fs::File::create(&tmp)?;
+        serde_json::to_writer(&file, &*self.failed_at.lock().unwrap())?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// The endpoint every other mirror is checked against.
+    pub fn primary(&self) -> &Mirror<'a> {
+        &self.mirrors[0]
+    }
+
+    /// Listings needed to trust a hash without the primary, a majority of all mirrors.
+    pub fn quorum(&self) -> usize {
+        self.mirrors.len() / 2 + 1
+    }
+
+    pub fn is_healthy(&self, mirror: &Mirror<'_>) -> bool {
+        let now = chrono::Utc::now().timestamp();
+        match self.failed_at.lock().unwrap().get(mirror.list_api().as_ref()) {
+            Some(failed_at) => now - failed_at >= self.cooldown,
+            None => true,
+        }
+    }
+
+    /// Mirrors besides the primary out of cooldown, in configured order.
+    pub fn fallbacks(&self) -> impl Iterator<Item = &Mirror<'a>> {
+        self.mirrors[1..].iter().filter(move |mirror| {
+            let healthy = self.is_healthy(mirror);
+            if !healthy {
+                log::info!("skip {} in cooldown", mirror.list_api());
+            }
+            healthy
+        })
+    }
+
+    pub fn mark_failed(&self, mirror: &Mirror<'_>) {
+        let now = chrono::Utc::now().timestamp();
+        self.failed_at
+            .lock()
+            .unwrap()
+            .insert(mirror.list_api().into_owned(), now);
+    }
+
+    pub fn mark_ok(&self, mirror: &Mirror<'_>) {
+        self.failed_at.lock().unwrap().remove(mirror.list_api().as_ref());
+    }
+}
+
+/// A listing is trusted for an object only when it agrees with the reference listing on its hash.
+pub fn agrees<'t>(reference: &Meta, listing: &'t MetaTable) -> Option<&'t Meta> {
+    listing
+        .iter()
+        .find(|meta| meta.name == reference.name && meta.md5_hash == reference.md5_hash)
+}
+
+/// Drop the objects of `reference` fewer than `quorum` of `listings` agree on, `reference` counted as one.
+pub fn retain_agreed(reference: &mut MetaTable, listings: &[&MetaTable], quorum: usize) {
+    reference.retain(|meta| {
+        1 + listings
+            .iter()
+            .filter(|listing| agrees(meta, listing).is_some())
+            .count()
+            >= quorum
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cooldown() {
+        let mirror = Mirror {
+            api_endpoint: "http://127.0.0.1/".into(),
+            bucket_name: "aaa".into(),
+            token: None,
+        };
+        let data_dir = std::env::temp_dir().join("seiran-test-cooldown");
+        let mirrors = Mirrors::load(vec![mirror.clone()], 600, data_dir.clone().into());
+        mirrors.mark_failed(&mirror);
+        assert!(!mirrors.is_healthy(&mirror));
+        mirrors.mark_ok(&mirror);
+        assert!(mirrors.is_healthy(&mirror));
+        let mirrors = Mirrors::load(vec![mirror.clone()], 0, data_dir.into());
+        mirrors.mark_failed(&mirror);
+        assert!(mirrors.is_healthy(&mirror));
+    }
+
+    #[test]
+    fn quorum() {
+        let meta = |name: &str, md5: &str| Meta {
+            name: name.into(),
+            md5_hash: md5.into(),
+            ..Default::default()
+        };
+        let table = |items: Vec<Meta>| items.into_iter().collect::<MetaTable>();
+        let mut reference = table(vec![meta("aaa", "1"), meta("bbb", "1")]);
+        let others = [
+            table(vec![meta("aaa", "1"), meta("bbb", "2")]),
+            table(vec![meta("bbb", "3")]),
+        ];
+        retain_agreed(&mut reference, &others.iter().collect::<Vec<_>>(), 2);
+        assert_eq!(
+            vec!["aaa"],
+            reference.iter().map(|meta| meta.name.as_str()).collect::<Vec<_>>()
+        );
+    }
+}
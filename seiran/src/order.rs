@@ -0,0 +1,184 @@
+use crate::meta::Meta;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+/// custom metadata key listing, comma separated, the file names an object is installed after
+pub const AFTER_KEY: &str = "seiran-after";
+/// custom metadata key naming the group an object is installed all-or-nothing with
+pub const GROUP_KEY: &str = "seiran-group";
+
+/// How an object is installed along the others, `[objects.<file name>]` in the config.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Object {
+    /// file names installed before this one, when they are updated in the same sync
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// objects of a group are installed together, or none of them are
+    #[serde(default)]
+    pub group: Option<String>,
+}
+
+impl Object {
+    /// Declared for `meta` by the config, then by its metadata.
+    pub fn of(meta: &Meta, configured: Option<&Object>) -> Self {
+        let mut object = configured.cloned().unwrap_or_default();
+        if object.after.is_empty() {
+            if let Some(after) = meta.metadata.get(AFTER_KEY) {
+                object.after = after
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                    .map(ToOwned::to_owned)
+                    .collect();
+            }
+        }
+        if object.group.is_none() {
+            object.group = meta.metadata.get(GROUP_KEY).cloned();
+        }
+        object
+    }
+}
+
+/// Indices `0..deps.len()` with each after those it depends on, the earliest ready first,
+/// then those left in a cycle or after one.
+fn toposort(deps: &[Vec<usize>]) -> (Vec<usize>, Vec<usize>) {
+    let mut done = vec![false; deps.len()];
+    let mut order = Vec::with_capacity(deps.len());
+    while let Some(next) = (0..deps.len()).find(|&i| !done[i] && deps[i].iter().all(|&dep| done[dep] || dep == i)) {
+        done[next] = true;
+        order.push(next);
+    }
+    (order, (0..deps.len()).filter(|&i| !done[i]).collect())
+}
+
+/// `delta` split into install units in order, and what no order fits.
+#[derive(Default)]
+pub struct Sorted {
+    /// a group or a single object each, after the units they depend on
+    pub units: Vec<Vec<Meta>>,
+    /// objects in a dependency cycle or after one, a whole group when its members are
+    pub cycles: Vec<Vec<Meta>>,
+}
+
+/// Split `delta` into install units, each a group or a single object, ordered by the dependencies between them.
+///
+/// Dependencies on objects not in `delta` are met already.
+pub fn sort(delta: Vec<Meta>, objects: &HashMap<String, Object>) -> Sorted {
+    let declared: Vec<_> = delta
+        .iter()
+        .map(|meta| Object::of(meta, objects.get(&meta.name())))
+        .collect();
+    let index: HashMap<_, _> = delta.iter().enumerate().map(|(i, meta)| (meta.name(), i)).collect();
+    // the unit of each object, keyed by group or by its own name
+    let mut units: Vec<String> = Vec::new();
+    let unit_of: Vec<usize> = delta
+        .iter()
+        .zip(&declared)
+        .map(|(meta, object)| {
+            let key = object.group.clone().unwrap_or_else(|| format!("/{}", meta.name()));
+            match units.iter().position(|unit| *unit == key) {
+                Some(unit) => unit,
+                None => {
+                    units.push(key);
+                    units.len() - 1
+                }
+            }
+        })
+        .collect();
+    let deps: Vec<Vec<usize>> = declared
+        .iter()
+        .map(|object| {
+            object
+                .after
+                .iter()
+                .filter_map(|name| index.get(name).copied())
+                .collect()
+        })
+        .collect();
+    let mut unit_deps = vec![Vec::new(); units.len()];
+    for (i, deps) in deps.iter().enumerate() {
+        unit_deps[unit_of[i]].extend(deps.iter().map(|&dep| unit_of[dep]));
+    }
+    let (unit_order, left) = toposort(&unit_deps);
+    let mut delta: Vec<_> = delta.into_iter().map(Some).collect();
+    let mut sorted = Sorted::default();
+    if !left.is_empty() {
+        sorted.cycles.push(
+            (0..delta.len())
+                .filter(|&i| left.contains(&unit_of[i]))
+                .filter_map(|i| delta[i].take())
+                .collect(),
+        );
+    }
+    for unit in unit_order {
+        let members: Vec<_> = (0..delta.len()).filter(|&i| unit_of[i] == unit).collect();
+        let member_deps: Vec<Vec<usize>> = members
+            .iter()
+            .map(|&i| {
+                deps[i]
+                    .iter()
+                    .filter_map(|dep| members.iter().position(|m| m == dep))
+                    .collect()
+            })
+            .collect();
+        let (order, left) = toposort(&member_deps);
+        let unit = order.into_iter().chain(left.iter().copied());
+        let unit = unit.filter_map(|m| delta[members[m]].take()).collect();
+        if left.is_empty() {
+            sorted.units.push(unit);
+        } else {
+            sorted.cycles.push(unit);
+        }
+    }
+    sorted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dependencies_and_groups() {
+        let meta = |name: &str, metadata: &[(&str, &str)]| Meta {
+            name: format!("dir/{}", name),
+            metadata: metadata.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+            ..Default::default()
+        };
+        let delta = vec![
+            meta("app", &[(AFTER_KEY, "lib, gone")]),
+            meta("app.toml", &[(GROUP_KEY, "app")]),
+            meta("tool", &[]),
+            meta("lib", &[]),
+        ];
+        let objects = HashMap::from_iter([(
+            "app".to_owned(),
+            Object {
+                group: Some("app".into()),
+                ..Default::default()
+            },
+        )]);
+        let names = |units: &[Vec<Meta>]| -> Vec<Vec<String>> {
+            units.iter().map(|unit| unit.iter().map(Meta::name).collect()).collect()
+        };
+        let sorted = sort(delta.clone(), &objects);
+        assert_eq!(
+            vec![vec!["tool"], vec!["lib"], vec!["app", "app.toml"]],
+            names(&sorted.units)
+        );
+        assert!(sorted.cycles.is_empty());
+        // the cycle and what comes after it are left out, the rest is still sorted
+        let mut cyclic = delta.clone();
+        cyclic[3] = meta("lib", &[(AFTER_KEY, "app.toml")]);
+        cyclic.push(meta("plugin", &[(AFTER_KEY, "app")]));
+        let sorted = sort(cyclic, &objects);
+        assert_eq!(vec![vec!["tool"]], names(&sorted.units));
+        assert_eq!(vec![vec!["app", "app.toml", "lib", "plugin"]], names(&sorted.cycles));
+        // a cycle within a group leaves the whole group out
+        let mut cyclic = delta;
+        cyclic[1] = meta("app.toml", &[(GROUP_KEY, "app"), (AFTER_KEY, "app")]);
+        cyclic[0] = meta("app", &[(AFTER_KEY, "app.toml")]);
+        let sorted = sort(cyclic, &objects);
+        assert_eq!(vec![vec!["tool"], vec!["lib"]], names(&sorted.units));
+        assert_eq!(vec![vec!["app", "app.toml"]], names(&sorted.cycles));
+    }
+}
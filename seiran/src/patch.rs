@@ -0,0 +1,76 @@
+use crate::{meta::Meta, perm};
+use std::{
+    fs,
+    io::{self, BufReader, Seek, SeekFrom},
+    os::unix::fs::PermissionsExt,
+    path,
+};
+
+/// cache subdir patches are downloaded to
+pub const PATCH_DIR: &str = "patches";
+
+/// largest window a patch may use, `zstd --long=31`
+const WINDOW_LOG_MAX: u32 = 31;
+/// largest installed file patched from, a patch cannot refer further back than its window
+const MAX_REFERENCE: u64 = 1 << WINDOW_LOG_MAX;
+
+fn hex_md5(md5_hash: &str) -> Option<String> {
+    base64::decode(md5_hash).ok().map(hex::encode)
+}
+
+/// Name of the patch object from `installed` to `target`, `<prefix><name>/<from md5>-<to md5>.zst` in hex.
+pub fn patch_name(prefix: &str, installed: &Meta, target: &Meta) -> Option<String> {
+    Some(format!(
+        "{}{}/{}-{}.zst",
+        prefix,
+        target.name,
+        hex_md5(installed.content_md5())?,
+        hex_md5(&target.md5_hash)?
+    ))
+}
+
+/// Rebuild the target at `desc` from the installed file at `old` and a `zstd --patch-from` patch.
+pub fn apply(old: &path::Path, mut patch: &fs::File, desc: &path::Path) -> anyhow::Result<fs::File> {
+    patch.seek(SeekFrom::Start(0))?;
+    let old = fs::File::open(old)?;
+    if old.metadata()?.len() > MAX_REFERENCE {
+        return Err(anyhow::Error::msg("Installed file too large to patch from."));
+    }
+    // SAFETY: installed files are replaced by renaming over them, never written to in place.
+    // It is mapped rather than read, so only the pages the patch refers to are loaded.
+    let reference = unsafe { memmap2::Mmap::map(&old)? };
+    let mut decoder = zstd::stream::read::Decoder::with_ref_prefix(BufReader::new(patch), &reference[..])?;
+    decoder.window_log_max(WINDOW_LOG_MAX)?;
+    let mut file = fs::File::options()
+        .read(true)
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(desc)?;
+    file.set_permissions(fs::Permissions::from_mode(perm::CACHE_MODE))?;
+    io::copy(&mut decoder, &mut file)?;
+    file.sync_all()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn apply_patch_from() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join("seiran-test-patch");
+        fs::create_dir_all(&dir)?;
+        let old: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+        let mut new = old.clone();
+        new[5000..5010].copy_from_slice(b"0123456789");
+        fs::write(dir.join("old"), &old)?;
+        let mut encoder = zstd::stream::write::Encoder::with_ref_prefix(Vec::new(), 3, &old)?;
+        encoder.write_all(&new)?;
+        fs::write(dir.join("patch"), encoder.finish()?)?;
+        apply(&dir.join("old"), &fs::File::open(dir.join("patch"))?, &dir.join("new"))?;
+        assert_eq!(new, fs::read(dir.join("new"))?);
+        Ok(())
+    }
+}
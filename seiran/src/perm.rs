@@ -0,0 +1,164 @@
+use nix::unistd::{self, Gid, Group, Uid, User};
+use regex::Regex;
+use serde::{de::Error, Deserialize, Deserializer};
+use std::{
+    fs,
+    os::unix::fs::{MetadataExt, PermissionsExt},
+    path,
+};
+
+/// mode of cached files, only the user running seiran reads or runs them
+pub const CACHE_MODE: u32 = 0o700;
+
+fn pattern<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    Regex::new(&String::deserialize(deserializer)?)
+        .map(Some)
+        .map_err(D::Error::custom)
+}
+
+fn octal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(&mode, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| D::Error::custom(format!("invalid mode {}, expect octal like \"0755\"", mode)))
+}
+
+fn mode() -> u32 {
+    0o755
+}
+
+/// Owner and mode of installed files, `[[permissions]]` in the config, the first matching one applies.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Permission {
+    /// regex of installed file names, every file when unset
+    #[serde(rename = "match", default, deserialize_with = "pattern")]
+    pattern: Option<Regex>,
+    /// user name or uid, only applied when running as root
+    #[serde(default)]
+    owner: Option<String>,
+    /// group name or gid, only applied when running as root
+    #[serde(default)]
+    group: Option<String>,
+    #[serde(default = "mode", deserialize_with = "octal")]
+    mode: u32,
+}
+
+impl Default for Permission {
+    fn default() -> Self {
+        Self {
+            pattern: None,
+            owner: None,
+            group: None,
+            mode: mode(),
+        }
+    }
+}
+
+impl Permission {
+    /// The same owner and group, with `mode` instead.
+    pub fn with_mode(&self, mode: u32) -> Self {
+        Self { mode, ..self.clone() }
+    }
+
+    /// The same permission for a dir, searchable by whoever may read it.
+    pub fn for_dir(&self) -> Self {
+        self.with_mode(self.mode | (self.mode & 0o444) >> 2)
+    }
+
+    pub fn is_match(&self, name: &str) -> bool {
+        self.pattern.as_ref().is_none_or(|pattern| pattern.is_match(name))
+    }
+
+    fn uid(&self) -> anyhow::Result<Option<Uid>> {
+        let owner = match self.owner {
+            Some(ref owner) => owner,
+            None => return Ok(None),
+        };
+        match owner.parse() {
+            Ok(uid) => Ok(Some(Uid::from_raw(uid))),
+            Err(_) => Ok(Some(
+                User::from_name(owner)?
+                    .ok_or_else(|| anyhow::Error::msg(format!("No user {}.", owner)))?
+                    .uid,
+            )),
+        }
+    }
+
+    fn gid(&self) -> anyhow::Result<Option<Gid>> {
+        let group = match self.group {
+            Some(ref group) => group,
+            None => return Ok(None),
+        };
+        match group.parse() {
+            Ok(gid) => Ok(Some(Gid::from_raw(gid))),
+            Err(_) => Ok(Some(
+                Group::from_name(group)?
+                    .ok_or_else(|| anyhow::Error::msg(format!("No group {}.", group)))?
+                    .gid,
+            )),
+        }
+    }
+
+    /// Set the mode of `file`, and its owner when running as root.
+    pub fn apply(&self, file: &path::Path) -> anyhow::Result<()> {
+        if is_root() {
+            unistd::chown(file, self.uid()?, self.gid()?)?;
+        } else if self.owner.is_some() || self.group.is_some() {
+            log::debug!("not root, keep the owner of {}", file.display());
+        }
+        fs::set_permissions(file, fs::Permissions::from_mode(self.mode))?;
+        Ok(())
+    }
+}
+
+pub fn is_root() -> bool {
+    unistd::geteuid().is_root()
+}
+
+/// `/usr/local/bin` for root, `~/.local/bin` for everyone else.
+pub fn default_install_dir() -> Option<path::PathBuf> {
+    if is_root() {
+        return Some("/usr/local/bin".into());
+    }
+    dirs::executable_dir().or_else(|| Some(dirs::home_dir()?.join(".local/bin")))
+}
+
+/// Create `dir` if missing, and refuse it when another user owns it.
+///
+/// Another user could swap the files seiran verified, or read what it should not.
+pub fn check_dir(dir: &path::Path) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    let owner = fs::metadata(dir)?.uid();
+    let user = unistd::geteuid();
+    if owner != user.as_raw() {
+        return Err(anyhow::Error::msg(format!(
+            "{} is owned by uid {}, not by uid {} running seiran.",
+            dir.display(),
+            owner,
+            user
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn permission() -> anyhow::Result<()> {
+        let permission: Permission = toml::from_str(
+            r#"
+                match = "^svc-"
+                owner = "0"
+                mode = "0750"
+            "#,
+        )?;
+        assert!(permission.is_match("svc-agent") && !permission.is_match("tool"));
+        assert_eq!(0o750, permission.mode);
+        assert_eq!(Some(Uid::from_raw(0)), permission.uid()?);
+        assert!(toml::from_str::<Permission>(r#"mode = "0855""#).is_err());
+        Ok(())
+    }
+}
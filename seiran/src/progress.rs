@@ -0,0 +1,144 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+const TEMPLATE: &str = "{msg:24!} [{bar:30}] {bytes}/{total_bytes} {percent:>3}% {bytes_per_sec} ETA {eta}";
+
+fn style() -> ProgressStyle {
+    ProgressStyle::with_template(TEMPLATE)
+        .expect("valid progress template")
+        .progress_chars("=> ")
+}
+
+/// Download progress bars, drawn only when stdout is a terminal.
+pub struct Bars {
+    multi: MultiProgress,
+    /// aggregate bar with the number of downloads left, only when more than one object is downloaded
+    total: Mutex<Option<(ProgressBar, usize)>>,
+    /// bar and bytes received of each download in progress
+    objects: Mutex<HashMap<String, (ProgressBar, u64)>>,
+    /// downloads that failed, counted again when retried from another mirror
+    abandoned: Mutex<HashSet<String>>,
+}
+
+impl Bars {
+    pub fn new() -> Self {
+        Self::with_draw_target(ProgressDrawTarget::stdout())
+    }
+
+    fn with_draw_target(target: ProgressDrawTarget) -> Self {
+        Self {
+            multi: MultiProgress::with_draw_target(target),
+            total: Mutex::new(None),
+            objects: Mutex::new(HashMap::new()),
+            abandoned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    pub fn is_hidden(&self) -> bool {
+        self.multi.is_hidden()
+    }
+
+    pub fn plan(&self, objects: usize, bytes: u64) {
+        let mut total = self.total.lock().unwrap();
+        if let Some((bar, _)) = total.take() {
+            bar.finish_and_clear();
+        }
+        self.abandoned.lock().unwrap().clear();
+        if objects > 1 {
+            let bar = self.multi.add(ProgressBar::new(bytes));
+            bar.set_style(style());
+            bar.set_message(format!("total ({} objects)", objects));
+            *total = Some((bar, objects));
+        }
+    }
+
+    pub fn start(&self, name: &str, size: u64) {
+        let bar = ProgressBar::new(size);
+        let bar = match *self.total.lock().unwrap() {
+            Some((ref total, ref mut left)) => {
+                if self.abandoned.lock().unwrap().remove(name) {
+                    *left += 1;
+                }
+                self.multi.insert_before(total, bar)
+            }
+            None => self.multi.add(bar),
+        };
+        bar.set_style(style());
+        bar.set_message(name.to_owned());
+        self.objects.lock().unwrap().insert(name.to_owned(), (bar, 0));
+    }
+
+    pub fn inc(&self, name: &str, delta: u64) {
+        if let Some((bar, received)) = self.objects.lock().unwrap().get_mut(name) {
+            bar.inc(delta);
+            *received += delta;
+        }
+        if let Some((ref total, _)) = *self.total.lock().unwrap() {
+            total.inc(delta);
+        }
+    }
+
+    pub fn finish(&self, name: &str) {
+        if let Some((bar, _)) = self.objects.lock().unwrap().remove(name) {
+            bar.finish();
+        }
+        self.finish_total();
+    }
+
+    pub fn abandon(&self, name: &str) {
+        if let Some((bar, received)) = self.objects.lock().unwrap().remove(name) {
+            bar.abandon_with_message(format!("{} failed", name));
+            // the total counts each object once, whichever attempt completes it
+            if let Some((ref total, _)) = *self.total.lock().unwrap() {
+                total.set_position(total.position().saturating_sub(received));
+            }
+        }
+        self.abandoned.lock().unwrap().insert(name.to_owned());
+        self.finish_total();
+    }
+
+    /// finish the aggregate bar once every planned download is done
+    fn finish_total(&self) {
+        let mut total = self.total.lock().unwrap();
+        if let Some((ref bar, ref mut left)) = *total {
+            *left = left.saturating_sub(1);
+            if *left == 0 {
+                bar.finish();
+                *total = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn total_through_retry() {
+        let bars = Bars::with_draw_target(ProgressDrawTarget::hidden());
+        let total = || {
+            let total = bars.total.lock().unwrap();
+            total.as_ref().map(|(bar, left)| (bar.position(), *left))
+        };
+        bars.plan(2, 30);
+        bars.start("aaa", 10);
+        bars.inc("aaa", 6);
+        assert_eq!(Some((6, 2)), total());
+        // the first mirror breaks off, the next one sends it all again
+        bars.abandon("aaa");
+        assert_eq!(Some((0, 1)), total());
+        bars.start("aaa", 10);
+        bars.inc("aaa", 10);
+        bars.finish("aaa");
+        assert_eq!(Some((10, 1)), total());
+        bars.start("bbb", 20);
+        bars.inc("bbb", 20);
+        assert_eq!(Some((30, 1)), total());
+        bars.finish("bbb");
+        assert_eq!(None, total());
+    }
+}
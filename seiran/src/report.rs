@@ -0,0 +1,435 @@
+use crate::{history::Record, progress};
+use colored::Colorize;
+use serde::Serialize;
+use std::{
+    future::Future,
+    io::{self, Write},
+    path::Path,
+};
+
+/// A unit of work that begins and then either finishes or fails.
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step<'a> {
+    LoadConfig {
+        path: &'a Path,
+    },
+    FetchMeta {
+        uri: &'a str,
+    },
+    CheckSpace {
+        bytes: u64,
+    },
+    Download {
+        name: &'a str,
+        size: u64,
+    },
+    Check {
+        name: &'a str,
+    },
+    /// rebuild an object from the installed version and a patch
+    Patch {
+        name: &'a str,
+    },
+    /// run a new binary before installing it
+    SmokeTest {
+        name: &'a str,
+    },
+    Install {
+        name: &'a str,
+    },
+    /// restore the version a member of a failed group replaced
+    Rollback {
+        name: &'a str,
+    },
+    /// unlink an object no longer synced
+    Remove {
+        name: &'a str,
+    },
+}
+
+/// Where an object stands on this host.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    Installed,
+    Pending,
+    /// released to a share of hosts this one is not within yet
+    DeferredByRollout,
+    /// verified into the cache, installed once a window opens
+    DeferredByWindow,
+    /// verified into the cache, installed after an object it depends on, which waits for a window
+    DeferredByDependency,
+}
+
+/// How bad a finding of `seiran doctor` is.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Severity {
+    Ok,
+    /// works, but not as intended
+    Warning,
+    /// seiran fails until it is fixed
+    Error,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Config {
+        data_dir: &'a Path,
+        cache_dir: &'a Path,
+        install_dir: &'a Path,
+    },
+    /// the following events are about this `[[source]]`
+    Source {
+        name: &'a str,
+    },
+    Begin(Step<'a>),
+    Done(Step<'a>),
+    Failed {
+        #[serde(flatten)]
+        step: Step<'a>,
+        error: String,
+    },
+    /// bytes received since the last progress event of the same object
+    Progress {
+        name: &'a str,
+        bytes: u64,
+    },
+    /// objects to be synced in this run
+    Plan {
+        objects: usize,
+        bytes: u64,
+    },
+    Summary {
+        installed: usize,
+        failed: &'a [String],
+    },
+    /// summaries of every `[[source]]` of a run together
+    Total {
+        installed: usize,
+        /// objects, prefixed with their source
+        failed: &'a [String],
+        /// sources that did not sync at all
+        failed_sources: &'a [String],
+    },
+    Status {
+        name: &'a str,
+        state: State,
+    },
+    /// store garbage collected
+    Gc {
+        generations: usize,
+        entries: usize,
+        bytes: u64,
+    },
+    /// cache files removed by the cache policy
+    CacheGc {
+        entries: usize,
+        bytes: u64,
+    },
+    /// a file in the cache, by `seiran cache ls`
+    CacheEntry {
+        name: &'a str,
+        /// hex content md5 of an installed version, none for a download not installed yet or from older seiran
+        version: Option<&'a str>,
+        bytes: u64,
+        modified: &'a str,
+    },
+    /// a line of the history log, by `seiran history`
+    History {
+        record: &'a Record,
+    },
+    /// effective config value and the layer it comes from
+    Setting {
+        key: &'a str,
+        value: &'a str,
+        origin: &'a str,
+    },
+    /// cache server listening
+    Serve {
+        addr: &'a str,
+    },
+    /// a check of `seiran doctor`
+    Finding {
+        check: &'a str,
+        severity: Severity,
+        detail: &'a str,
+        hint: Option<&'a str>,
+    },
+}
+
+/// Observes every event of a run, in order.
+pub trait Reporter: Send + Sync {
+    fn report(&self, event: &Event<'_>);
+}
+
+/// Drops every event, the default for embedders.
+pub struct Silent;
+
+impl Reporter for Silent {
+    fn report(&self, _: &Event<'_>) {}
+}
+
+/// Report `step` around `f`.
+pub fn step<T>(reporter: &dyn Reporter, step: Step<'_>, f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    reporter.report(&Event::Begin(step));
+    finish(reporter, step, f())
+}
+
+/// Report `step` around `f`, awaited.
+pub async fn step_async<T>(
+    reporter: &dyn Reporter,
+    step: Step<'_>,
+    f: impl Future<Output = anyhow::Result<T>>,
+) -> anyhow::Result<T> {
+    reporter.report(&Event::Begin(step));
+    finish(reporter, step, f.await)
+}
+
+fn finish<T>(reporter: &dyn Reporter, step: Step<'_>, res: anyhow::Result<T>) -> anyhow::Result<T> {
+    match res {
+        Ok(_) => reporter.report(&Event::Done(step)),
+        Err(ref e) => reporter.report(&Event::Failed {
+            step,
+            error: format!("{:#}", e),
+        }),
+    }
+    res
+}
+
+/// Colored output for people at a terminal.
+pub struct Human {
+    bars: progress::Bars,
+}
+
+impl Default for Human {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Human {
+    pub fn new() -> Self {
+        Self {
+            bars: progress::Bars::new(),
+        }
+    }
+
+    fn write(&self, out: &mut impl Write, event: &Event<'_>) -> io::Result<()> {
+        match *event {
+            Event::Config {
+                data_dir,
+                cache_dir,
+                install_dir,
+            } => {
+                writeln!(
+                    out,
+                    "{}\ndata dir: {}\ncache dir: {}\ninstall dir: {}",
+                    "::<> Check config.".blue(),
+                    data_dir.to_string_lossy().cyan(),
+                    cache_dir.to_string_lossy().cyan(),
+                    install_dir.to_string_lossy().cyan()
+                )?;
+                writeln!(out, "{}", "::<> Seiran.".blue())?;
+            }
+            Event::Source { name } => writeln!(out, "{} {}", "::<> Source".blue(), name.cyan())?,
+            Event::Begin(Step::Download { name, size }) => self.bars.start(name, size),
+            Event::Begin(step) => {
+                match step {
+                    Step::LoadConfig { path } => write!(out, "Load config from {}...", path.to_string_lossy().cyan())?,
+                    Step::FetchMeta { .. } => write!(out, "Fetch meta...")?,
+                    Step::CheckSpace { .. } => write!(out, "Check free space...")?,
+                    Step::Check { name } => write!(out, "Check {}...", name.cyan())?,
+                    Step::Patch { name } => write!(out, "Patch {}...", name.cyan())?,
+                    Step::SmokeTest { name } => write!(out, "Smoke test {}...", name.cyan())?,
+                    Step::Install { name } => write!(out, "Install {}...", name.cyan())?,
+                    Step::Rollback { name } => write!(out, "Roll back {}...", name.cyan())?,
+                    Step::Remove { name } => write!(out, "Remove {}...", name.cyan())?,
+                    Step::Download { .. } => unreachable!(),
+                }
+                out.flush()?;
+            }
+            Event::Done(Step::Download { name, .. }) => {
+                self.bars.finish(name);
+                // without a terminal, report each finished download on its own line
+                if self.bars.is_hidden() {
+                    writeln!(out, "Download {}...{}", name.cyan(), "OK".green())?;
+                }
+            }
+            Event::Done(_) => writeln!(out, "{}", "OK".green())?,
+            Event::Failed {
+                step: Step::Download { name, .. },
+                ref error,
+            } => {
+                self.bars.abandon(name);
+                if self.bars.is_hidden() {
+                    writeln!(out, "Download {}...{}: {}", name.cyan(), "Failed".red(), error)?;
+                }
+            }
+            Event::Failed { ref error, .. } => writeln!(out, "{}: {}", "Failed".red(), error)?,
+            Event::Progress { name, bytes } => self.bars.inc(name, bytes),
+            Event::Plan { objects: 0, .. } => writeln!(out, "{}", "No update.".green())?,
+            Event::Plan { objects, bytes } => self.bars.plan(objects, bytes),
+            Event::Summary { failed, .. } if !failed.is_empty() => {
+                writeln!(out, "{}", "::<> Summary.".blue())?;
+                writeln!(out, "{} failed: {}", failed.len(), failed.join(", ").red())?;
+            }
+            Event::Summary { .. } => {}
+            Event::Total {
+                installed,
+                failed,
+                failed_sources,
+            } => {
+                writeln!(out, "{}", "::<> Total.".blue())?;
+                writeln!(out, "{} installed", installed)?;
+                if !failed.is_empty() {
+                    writeln!(out, "{} failed: {}", failed.len(), failed.join(", ").red())?;
+                }
+                if !failed_sources.is_empty() {
+                    writeln!(
+                        out,
+                        "{} source(s) failed: {}",
+                        failed_sources.len(),
+                        failed_sources.join(", ").red()
+                    )?;
+                }
+            }
+            Event::Status { name, state } => {
+                let state = match state {
+                    State::Installed => "installed".green(),
+                    State::Pending => "pending".yellow(),
+                    State::DeferredByRollout => "deferred by rollout".yellow(),
+                    State::DeferredByWindow => "deferred by window".yellow(),
+                    State::DeferredByDependency => "deferred by dependency".yellow(),
+                };
+                writeln!(out, "{} {}", name.cyan(), state)?;
+            }
+            Event::Gc {
+                generations,
+                entries,
+                bytes,
+            } => writeln!(
+                out,
+                "Removed {} generation(s) and {} store entries, {} freed.",
+                generations,
+                entries,
+                indicatif::HumanBytes(bytes)
+            )?,
+            Event::CacheGc { entries, bytes } => writeln!(
+                out,
+                "Removed {} cache file(s), {} freed.",
+                entries,
+                indicatif::HumanBytes(bytes)
+            )?,
+            Event::CacheEntry {
+                name,
+                version,
+                bytes,
+                modified,
+            } => writeln!(
+                out,
+                "{:24} {:12} {:>10} {}",
+                name.cyan(),
+                version.map_or("unversioned", |version| &version[..12]),
+                indicatif::HumanBytes(bytes).to_string(),
+                modified
+            )?,
+            Event::History { record } => {
+                // the published version when there is one, the short md5 otherwise
+                let short = |version: &Option<String>, md5: &Option<String>| match version {
+                    Some(version) => version.clone(),
+                    None => md5
+                        .as_deref()
+                        .map_or("-".to_owned(), |md5| md5.chars().take(8).collect()),
+                };
+                write!(
+                    out,
+                    "{} {:20} {} {} -> {}",
+                    record.time,
+                    record.action.as_str(),
+                    record.name.cyan(),
+                    short(&record.old_version, &record.old_md5),
+                    short(&record.new_version, &record.new_md5)
+                )?;
+                match record.error {
+                    Some(ref error) => writeln!(out, " {}", error.red())?,
+                    None => writeln!(out)?,
+                }
+            }
+            Event::Setting { key, value, origin } => {
+                writeln!(out, "{} = {} {}", key.cyan(), value, format!("# {}", origin).dimmed())?
+            }
+            Event::Serve { addr } => writeln!(out, "{} {}", "::<> Serving on".blue(), addr.cyan())?,
+            Event::Finding {
+                check,
+                severity,
+                detail,
+                hint,
+            } => {
+                let severity = match severity {
+                    Severity::Ok => "ok".green(),
+                    Severity::Warning => "warning".yellow(),
+                    Severity::Error => "error".red(),
+                };
+                writeln!(out, "[{}] {}: {}", severity, check.cyan(), detail)?;
+                if let Some(hint) = hint {
+                    writeln!(out, "  {}", hint.dimmed())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Reporter for Human {
+    fn report(&self, event: &Event<'_>) {
+        // a closed stdout, like `seiran status | head -1`, is no reason to fail the run
+        self.write(&mut io::stdout(), event).ok();
+    }
+}
+
+/// One JSON object per line, for machines.
+pub struct Json;
+
+#[derive(Serialize)]
+struct Line<'a> {
+    time: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+impl Reporter for Json {
+    fn report(&self, event: &Event<'_>) {
+        // per chunk progress is too chatty to be useful for machines
+        if let Event::Progress { .. } = event {
+            return;
+        }
+        let line = Line {
+            time: chrono::offset::Local::now().to_rfc3339(),
+            event,
+        };
+        let line = serde_json::to_string(&line).expect("events are serializable");
+        // the reader may be gone, like `head`, the sync goes on without it
+        writeln!(io::stdout().lock(), "{}", line).ok();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn failed_event_is_flat() -> anyhow::Result<()> {
+        let event = Event::Failed {
+            step: Step::Check { name: "ccc" },
+            error: "md5 mismatch.".into(),
+        };
+        assert_eq!(
+            r#"{"event":"failed","step":"check","name":"ccc","error":"md5 mismatch."}"#,
+            serde_json::to_string(&event)?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,126 @@
+use crate::{client::Client, meta::Meta, mirror::Mirror};
+use md5::Digest;
+use std::{collections::HashMap, fs};
+
+/// custom metadata key holding the percentage of hosts an object is released to
+pub const METADATA_KEY: &str = "seiran-rollout";
+
+/// files tried in order for a stable host identifier
+const HOST_ID_FILES: &[&str] = &[
+    "/etc/machine-id",
+    "/var/lib/dbus/machine-id",
+    "/proc/sys/kernel/hostname",
+];
+
+/// Stable identifier of this host, the configured one first.
+pub fn host_id(configured: Option<&str>) -> anyhow::Result<String> {
+    if let Some(id) = configured {
+        return Ok(id.to_owned());
+    }
+    HOST_ID_FILES
+        .iter()
+        .filter_map(|file| fs::read_to_string(file).ok())
+        .map(|id| id.trim().to_owned())
+        .find(|id| !id.is_empty())
+        .ok_or_else(|| anyhow::Error::msg("No host id found, set host_id in config."))
+}
+
+/// Position of this host in the rollout of `name`, from 0 to 99.
+///
+/// The name is hashed in, so the same hosts are not always the first to get every object.
+/// It is the name and not the id, so a host keeps its position across versions.
+pub fn bucket(host_id: &str, name: &str) -> u8 {
+    let mut hasher = md5::Md5::new();
+    hasher.update(host_id);
+    hasher.update("/");
+    hasher.update(name);
+    let digest = hasher.finalize();
+    let mut head = [0u8; 8];
+    head.copy_from_slice(&digest[..8]);
+    (u64::from_be_bytes(head) % 100) as u8
+}
+
+/// Rollout percentages by object name, overriding the object metadata.
+pub async fn fetch_manifest(meta: &Meta, mirror: &Mirror<'_>, client: &Client) -> anyhow::Result<HashMap<String, u32>> {
+    log::debug!("GET {}", meta.media_link);
+    Ok(mirror
+        .get(client, &meta.media_link)
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?)
+}
+
+/// Percentage of hosts `meta` is released to, `None` for every host.
+///
+/// A value that does not read as a percentage releases to no host, a typo must not release to all of them.
+pub fn percent(meta: &Meta, manifest: &HashMap<String, u32>) -> Option<u8> {
+    let percent = match manifest.get(&meta.name) {
+        Some(&percent) => percent,
+        None => {
+            let value = meta.metadata.get(METADATA_KEY)?;
+            match value.trim().trim_end_matches('%').parse() {
+                Ok(percent) => percent,
+                Err(_) => {
+                    log::warn!(
+                        "{} {} of {} is not a percentage, held back",
+                        METADATA_KEY,
+                        value,
+                        meta.name
+                    );
+                    0
+                }
+            }
+        }
+    };
+    Some(percent.min(100) as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rollout_grows() {
+        let hosts: Vec<_> = (0..1000).map(|i| format!("host-{}", i)).collect();
+        let within = |percent| hosts.iter().filter(|host| bucket(host, "dir/ccc") < percent).count();
+        assert!((150..250).contains(&within(20)));
+        // raising the percentage keeps every host already within
+        for host in hosts.iter().filter(|host| bucket(host, "dir/ccc") < 20) {
+            assert!(bucket(host, "dir/ccc") < 50);
+        }
+        assert_eq!(1000, within(100));
+    }
+
+    #[test]
+    fn manifest_overrides_metadata() {
+        let mut meta = Meta {
+            name: "dir/ccc".into(),
+            ..Default::default()
+        };
+        let mut manifest = HashMap::new();
+        assert_eq!(None, percent(&meta, &manifest));
+        meta.metadata.insert(METADATA_KEY.into(), "10%".into());
+        assert_eq!(Some(10), percent(&meta, &manifest));
+        manifest.insert("dir/ccc".into(), 30);
+        assert_eq!(Some(30), percent(&meta, &manifest));
+        manifest.insert("dir/ccc".into(), 300);
+        assert_eq!(Some(100), percent(&meta, &manifest));
+    }
+
+    #[test]
+    fn malformed_holds_back() {
+        let with = |value: &str| Meta {
+            name: "dir/ccc".into(),
+            metadata: HashMap::from_iter([(METADATA_KEY.to_owned(), value.to_owned())]),
+            ..Default::default()
+        };
+        for value in ["12.5", "50 %", "-1", "half"] {
+            assert_eq!(Some(0), percent(&with(value), &HashMap::new()), "{}", value);
+        }
+        // beyond u8, still every host
+        assert_eq!(Some(100), percent(&with("300"), &HashMap::new()));
+        assert_eq!(Some(100), percent(&with(" 100% "), &HashMap::new()));
+    }
+}
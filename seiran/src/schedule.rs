@@ -0,0 +1,99 @@
+use chrono::{DateTime, Datelike, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use serde::{de::Error, Deserialize, Deserializer};
+
+fn weekdays<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Weekday>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|day| {
+            day.parse()
+                .map_err(|_| D::Error::custom(format!("invalid day {}", day)))
+        })
+        .collect()
+}
+
+fn time<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M")
+        .map_err(|_| D::Error::custom(format!("invalid time {}, expect HH:MM", time)))
+}
+
+fn timezone<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Tz>, D::Error> {
+    let timezone = String::deserialize(deserializer)?;
+    timezone.parse().map(Some).map_err(D::Error::custom)
+}
+
+/// Time of the week installs are allowed in, e.g. `{ days = ["sat", "sun"], start = "02:00", end = "05:00" }`.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Window {
+    /// days the window opens on, every day when empty
+    #[serde(default, deserialize_with = "weekdays")]
+    days: Vec<Weekday>,
+    /// HH:MM, a window ending before it starts closes the next day
+    #[serde(deserialize_with = "time")]
+    start: NaiveTime,
+    #[serde(deserialize_with = "time")]
+    end: NaiveTime,
+    /// IANA name like Asia/Tokyo, default to the local timezone
+    #[serde(default, deserialize_with = "timezone")]
+    timezone: Option<Tz>,
+}
+
+impl Window {
+    fn opens_on(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    fn contains_local<T: TimeZone>(&self, now: DateTime<T>) -> bool {
+        let (day, time) = (now.weekday(), now.time());
+        if self.start <= self.end {
+            self.opens_on(day) && self.start <= time && time < self.end
+        } else {
+            // across midnight, the morning part belongs to the window opened the day before
+            (self.opens_on(day) && self.start <= time) || (self.opens_on(day.pred()) && time < self.end)
+        }
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        match self.timezone {
+            Some(tz) => self.contains_local(now.with_timezone(&tz)),
+            None => self.contains_local(now.with_timezone(&chrono::Local)),
+        }
+    }
+}
+
+/// Whether installing is allowed at `now`, always when no window is configured.
+pub fn is_open(windows: &[Window], now: DateTime<Utc>) -> bool {
+    windows.is_empty() || windows.iter().any(|window| window.contains(now))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn window_across_midnight() -> anyhow::Result<()> {
+        let window: Window = toml::from_str(
+            r#"
+                days = ["sat"]
+                start = "23:00"
+                end = "02:00"
+                timezone = "Asia/Tokyo"
+            "#,
+        )?;
+        // saturday 2021-10-02 in Tokyo
+        let at = |day, hour, min| {
+            Tz::Asia__Tokyo
+                .with_ymd_and_hms(2021, 10, day, hour, min, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        assert!(!window.contains(at(2, 22, 59)));
+        assert!(window.contains(at(2, 23, 0)));
+        assert!(window.contains(at(3, 1, 59)));
+        assert!(!window.contains(at(3, 2, 0)));
+        assert!(!window.contains(at(1, 23, 30)));
+        assert!(is_open(&[], at(1, 12, 0)));
+        Ok(())
+    }
+}
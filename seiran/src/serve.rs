@@ -0,0 +1,295 @@
+use crate::{
+    check, database,
+    meta::{Meta, MetaTable, CONTENT_SIZE_KEY},
+    report::{Event, Reporter},
+    store::Store,
+    Config,
+};
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Request, Response, Server, StatusCode,
+};
+use serde::Serialize;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+use tokio_util::io::ReaderStream;
+
+/// path objects are served under, the listing points `mediaLink` here
+const DOWNLOAD: &str = "/download/";
+
+/// A store entry and its md5, valid as long as the file is unchanged.
+struct Verified {
+    modified: SystemTime,
+    len: u64,
+    md5_hash: String,
+}
+
+struct State {
+    data_dir: path::PathBuf,
+    store_dir: path::PathBuf,
+    verified: Mutex<HashMap<String, Verified>>,
+}
+
+#[derive(Serialize)]
+struct ListObjects {
+    items: Vec<Meta>,
+}
+
+impl State {
+    fn path(&self, meta: &Meta) -> Option<path::PathBuf> {
+        Some(self.store_dir.join(Store::entry(meta).ok()?))
+    }
+
+    /// Size of the stored copy of `meta` if it matches its content hash, hashing again only when the file changed.
+    async fn verify(&self, meta: &Meta) -> Option<u64> {
+        let path = self.path(meta)?;
+        let file = tokio::fs::File::open(&path).await.ok()?.into_std().await;
+        let metadata = file.metadata().ok()?;
+        let (modified, len) = (metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH), metadata.len());
+        let cached = self
+            .verified
+            .lock()
+            .unwrap()
+            .get(&meta.name)
+            .filter(|v| v.modified == modified && v.len == len)
+            .map(|v| v.md5_hash.clone());
+        let md5_hash = match cached {
+            Some(md5_hash) => md5_hash,
+            // hash off the runtime and without the lock, other requests go on meanwhile
+            None => {
+                let md5_hash = tokio::task::spawn_blocking(move || check::md5_sum(&file))
+                    .await
+                    .ok()?
+                    .ok()?;
+                let v = Verified {
+                    modified,
+                    len,
+                    md5_hash: md5_hash.clone(),
+                };
+                self.verified.lock().unwrap().insert(meta.name.clone(), v);
+                md5_hash
+            }
+        };
+        if md5_hash != meta.content_md5() {
+            log::warn!("{} does not match its hash, not served", path.display());
+            return None;
+        }
+        Some(len)
+    }
+
+    /// Installed objects whose stored copy is intact, linked to this server.
+    /// The store holds decoded content, so compressed objects are listed as plain ones.
+    async fn listing(&self, base: &str) -> ListObjects {
+        let table: MetaTable = database::load(Cow::Borrowed(&self.data_dir)).unwrap_or_default();
+        let mut items = Vec::new();
+        for meta in table.iter() {
+            if let Some(size) = self.verify(meta).await {
+                items.push(Meta {
+                    name: meta.content_name().to_owned(),
+                    media_link: format!("{}{}{}", base, DOWNLOAD, meta.name()),
+                    id: meta.id.clone(),
+                    md5_hash: meta.content_md5().to_owned(),
+                    size,
+                    // the rest describes the object to the hosts behind this one, but the stored bytes
+                    metadata: meta
+                        .metadata
+                        .iter()
+                        .filter(|(key, _)| *key != CONTENT_SIZE_KEY)
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect(),
+                    updated: meta.updated.clone(),
+                    generation: meta.generation,
+                    ..Default::default()
+                });
+            }
+        }
+        ListObjects { items }
+    }
+
+    async fn download(&self, name: &str) -> Option<Response<Body>> {
+        let table: MetaTable = database::load(Cow::Borrowed(&self.data_dir)).unwrap_or_default();
+        let meta = table.iter().find(|meta| meta.name() == name)?;
+        let size = self.verify(meta).await?;
+        let file = tokio::fs::File::open(self.path(meta)?).await.ok()?;
+        Response::builder()
+            .header(header::CONTENT_LENGTH, size)
+            .header(header::CONTENT_TYPE, "application/octet-stream")
+            .body(Body::wrap_stream(ReaderStream::new(file)))
+            .ok()
+    }
+
+    async fn handle(&self, req: Request<Body>) -> Response<Body> {
+        let path = req.uri().path();
+        log::info!("{} {}", req.method(), path);
+        let res = if path.starts_with("/b/") && path.ends_with("/o") {
+            let host = req
+                .headers()
+                .get(header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or("localhost");
+            let listing = self.listing(&format!("http://{}", host)).await;
+            serde_json::to_vec(&listing).ok().and_then(|body| {
+                Response::builder()
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body.into())
+                    .ok()
+            })
+        } else if let Some(name) = path.strip_prefix(DOWNLOAD) {
+            self.download(name).await
+        } else {
+            None
+        };
+        res.unwrap_or_else(|| {
+            let mut res = Response::new(Body::from("Not Found"));
+            *res.status_mut() = StatusCode::NOT_FOUND;
+            res
+        })
+    }
+}
+
+/// Serve the verified installed objects from the store, in the listing format seiran consumes,
+/// so other hosts can use this one as their `api_endpoint`.
+pub async fn serve(config: &Config<'_>, addr: SocketAddr, reporter: &dyn Reporter) -> anyhow::Result<()> {
+    let state = Arc::new(State {
+        data_dir: config.data_dir().into_owned(),
+        store_dir: config.store_dir().into_owned(),
+        verified: Mutex::new(HashMap::new()),
+    });
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(state.handle(req).await) }
+            }))
+        }
+    });
+    let server = Server::try_bind(&addr)?.serve(make_svc);
+    reporter.report(&Event::Serve {
+        addr: &server.local_addr().to_string(),
+    });
+    server.await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::perm::Permission;
+    use std::fs;
+
+    #[test]
+    fn listing_keeps_metadata() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-serve-{}", std::process::id()));
+        let (data_dir, store_dir) = (dir.join("data"), dir.join("store"));
+        let from = dir.join("ccc");
+        fs::create_dir_all(&dir)?;
+        fs::write(&from, "v1")?;
+        let meta = Meta {
+            name: "dir/ccc.zst".into(),
+            id: "bkt/dir/ccc.zst/1".into(),
+            md5_hash: "aaa".into(),
+            content_md5_hash: Some(check::md5_sum(&fs::File::open(&from)?)?),
+            metadata: HashMap::from_iter([
+                ("seiran-install-path".to_owned(), "sbin/ccc".to_owned()),
+                (CONTENT_SIZE_KEY.to_owned(), "8".to_owned()),
+            ]),
+            updated: Some("2021-10-04T08:00:00.000Z".into()),
+            generation: Some(1633334400000000),
+            ..Default::default()
+        };
+        Store::new(Cow::Borrowed(&data_dir), Cow::Borrowed(&store_dir)).add(&from, &meta, &Permission::default())?;
+        database::save(
+            Cow::Borrowed(&data_dir),
+            Cow::Owned(MetaTable::from_iter([meta.clone()])),
+        )?;
+        let state = State {
+            data_dir,
+            store_dir,
+            verified: Mutex::new(HashMap::new()),
+        };
+        let listing = rt.block_on(state.listing("http://host"));
+        fs::remove_dir_all(dir)?;
+        let served = &listing.items[0];
+        assert_eq!(("dir/ccc", 2), (served.name.as_str(), served.size));
+        assert_eq!("http://host/download/ccc", served.media_link);
+        assert_eq!(meta.content_md5(), served.md5_hash);
+        assert_eq!(
+            Some("sbin/ccc"),
+            served.metadata.get("seiran-install-path").map(String::as_str)
+        );
+        assert!(!served.metadata.contains_key(CONTENT_SIZE_KEY));
+        assert_eq!((&meta.updated, meta.generation), (&served.updated, served.generation));
+        Ok(())
+    }
+
+    #[test]
+    fn serves_verified_only() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let dir = std::env::temp_dir().join(format!("seiran-serve-verified-{}", std::process::id()));
+        let (data_dir, store_dir) = (dir.join("data"), dir.join("store"));
+        fs::create_dir_all(&dir)?;
+        let store = Store::new(Cow::Borrowed(&data_dir), Cow::Borrowed(&store_dir));
+        let mut table = Vec::new();
+        for name in ["good", "tampered", "truncated"] {
+            let from = dir.join(name);
+            fs::write(&from, format!("{} content", name))?;
+            let meta = Meta {
+                name: format!("dir/{}", name),
+                id: format!("bkt/dir/{}/1", name),
+                md5_hash: check::md5_sum(&fs::File::open(&from)?)?,
+                size: fs::metadata(&from)?.len(),
+                ..Default::default()
+            };
+            store.add(&from, &meta, &Permission::default().with_mode(0o644))?;
+            table.push(meta);
+        }
+        database::save(
+            Cow::Borrowed(&data_dir),
+            Cow::Owned(MetaTable::from_iter(table.clone())),
+        )?;
+        let entry = |meta: &Meta| Ok::<_, anyhow::Error>(store_dir.join(Store::entry(meta)?));
+        fs::write(entry(&table[1])?, "tampered contenT")?;
+        fs::write(entry(&table[2])?, "trunc")?;
+        let state = State {
+            data_dir: data_dir.clone(),
+            store_dir: store_dir.clone(),
+            verified: Mutex::new(HashMap::new()),
+        };
+        let get = |path: &str| {
+            rt.block_on(async {
+                let req = Request::get(path).header(header::HOST, "host").body(Body::empty())?;
+                let res = state.handle(req).await;
+                Ok::<_, anyhow::Error>((res.status(), hyper::body::to_bytes(res.into_body()).await?))
+            })
+        };
+        let (status, body) = get("/b/bkt/o")?;
+        assert_eq!(StatusCode::OK, status);
+        let listing: MetaTable = serde_json::from_slice(&body)?;
+        let names: Vec<_> = listing.iter().map(|meta| meta.name.as_str()).collect();
+        assert_eq!(vec!["dir/good"], names);
+        let (status, body) = get("/download/good")?;
+        assert_eq!((StatusCode::OK, &b"good content"[..]), (status, &body[..]));
+        for name in ["tampered", "truncated", "missing"] {
+            assert_eq!(StatusCode::NOT_FOUND, get(&format!("/download/{}", name))?.0);
+        }
+        // changed after it was verified, whatever its time says
+        fs::write(entry(&table[0])?, "good contenT")?;
+        fs::File::options()
+            .write(true)
+            .open(entry(&table[0])?)?
+            .set_modified(SystemTime::UNIX_EPOCH)?;
+        assert_eq!(StatusCode::NOT_FOUND, get("/download/good")?.0);
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
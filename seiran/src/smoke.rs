@@ -0,0 +1,82 @@
+use crate::report::{self, Reporter, Step};
+use serde::Deserialize;
+use std::{path, process::Stdio, time::Duration};
+
+fn args() -> Vec<String> {
+    vec!["--version".into()]
+}
+
+fn timeout() -> u64 {
+    10
+}
+
+/// Run a new binary from the cache before it replaces the installed one.
+#[derive(Deserialize, Clone, Debug)]
+pub struct SmokeTest {
+    /// arguments to run the binary with
+    #[serde(default = "args")]
+    pub args: Vec<String>,
+    /// seconds before the run counts as failed
+    #[serde(default = "timeout")]
+    pub timeout: u64,
+    /// regex the combined stdout and stderr must match, any output passes when unset
+    #[serde(default)]
+    pub expect: Option<String>,
+}
+
+async fn run(test: &SmokeTest, binary: &path::Path) -> anyhow::Result<()> {
+    let child = tokio::process::Command::new(binary)
+        .args(&test.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let output = tokio::time::timeout(Duration::from_secs(test.timeout), child.wait_with_output())
+        .await
+        .map_err(|_| anyhow::Error::msg(format!("No exit in {} seconds.", test.timeout)))??;
+    if !output.status.success() {
+        return Err(anyhow::Error::msg(format!("Exited with {}.", output.status)));
+    }
+    if let Some(ref expect) = test.expect {
+        let output = String::from_utf8_lossy(&output.stdout) + String::from_utf8_lossy(&output.stderr);
+        log::debug!("{}: {}", binary.display(), output);
+        if !regex::Regex::new(expect)?.is_match(&output) {
+            return Err(anyhow::Error::msg(format!("Output does not match {}.", expect)));
+        }
+    }
+    Ok(())
+}
+
+/// Smoke test the binary at `binary`, named `name`.
+pub async fn smoke_test(
+    test: &SmokeTest,
+    name: &str,
+    binary: &path::Path,
+    reporter: &dyn Reporter,
+) -> anyhow::Result<()> {
+    report::step_async(reporter, Step::SmokeTest { name }, run(test, binary)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn expect_output() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let sh = path::Path::new("/bin/sh");
+        let test = |args: &str, expect: &str| SmokeTest {
+            args: vec!["-c".into(), args.into()],
+            timeout: 1,
+            expect: Some(expect.into()),
+        };
+        rt.block_on(run(&test("echo tool 1.2.0", r"^tool 1\.2\.\d"), sh))?;
+        assert!(rt
+            .block_on(run(&test("echo tool 1.1.0", r"^tool 1\.2\.\d"), sh))
+            .is_err());
+        assert!(rt.block_on(run(&test("echo tool 1.2.0; exit 1", "tool"), sh)).is_err());
+        assert!(rt.block_on(run(&test("sleep 5", ""), sh)).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use crate::{meta::Meta, perm::Permission};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashSet},
+    fs,
+    os::unix::fs::{symlink, PermissionsExt},
+    path,
+};
+
+/// dir in the data dir holding the store by default
+pub const STORE_DIR: &str = "store";
+/// mode of the dirs created for the store, whatever the umask
+const DIR_MODE: u32 = 0o755;
+const GENERATIONS: &str = "generations.json";
+
+/// Installed files at one point, recorded after each sync that changes any.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Generation {
+    pub number: u64,
+    pub time: String,
+    /// installed file name -> store entry
+    pub entries: BTreeMap<String, String>,
+}
+
+/// What [`Store::gc`] removed.
+#[derive(Default, Debug)]
+pub struct Collected {
+    pub generations: usize,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// Verified objects keyed by content md5 in `dir`, installed as symlinks to them.
+/// The generations are kept in the data dir.
+pub struct Store<'a> {
+    data_dir: Cow<'a, path::Path>,
+    dir: Cow<'a, path::Path>,
+}
+
+impl<'a> Store<'a> {
+    pub fn new(data_dir: Cow<'a, path::Path>, dir: Cow<'a, path::Path>) -> Self {
+        Self { data_dir, dir }
+    }
+
+    pub fn dir(&self) -> path::PathBuf {
+        self.dir.to_path_buf()
+    }
+
+    /// Create the store, the dirs it takes traversable by everyone so the links work for every user.
+    fn create(&self) -> Result<()> {
+        create_dir_all(&self.dir, |dir| {
+            Ok(fs::set_permissions(dir, fs::Permissions::from_mode(DIR_MODE))?)
+        })
+    }
+
+    /// Store entry of `meta`, `<hex content md5>-<name>`.
+    pub fn entry(meta: &Meta) -> Result<String> {
+        let md5 = base64::decode(meta.content_md5())?;
+        Ok(format!("{}-{}", hex::encode(md5), meta.name()))
+    }
+
+    /// Copy the verified file `from` into the store, unless an entry with the same content is there already.
+    pub fn add(&self, from: &path::Path, meta: &Meta, permission: &Permission) -> Result<path::PathBuf> {
+        self.create()?;
+        let entry = self.dir.join(Self::entry(meta)?);
+        if entry.exists() {
+            // the permission may have changed in the config since
+            permission.apply(&entry)?;
+            return Ok(entry);
+        }
+        // copy aside then rename, an entry is either complete or missing
+        let tmp = entry.with_extension("tmp");
+        fs::copy(from, &tmp)?;
+        fs::File::open(&tmp)?.sync_all()?;
+        permission.apply(&tmp)?;
+        fs::rename(&tmp, &entry)?;
+        Ok(entry)
+    }
+
+    /// Point `install_dir/path` at `entry`, swapping the link in one rename.
+    /// The dirs created on the way get `permission`, searchable.
+    pub fn link(
+        entry: &path::Path,
+        install_dir: &path::Path,
+        path: &path::Path,
+        permission: &Permission,
+    ) -> Result<()> {
+        let to = install_dir.join(path);
+        let name = to.file_name().unwrap_or_default().to_string_lossy();
+        let tmp = to.with_file_name(format!(".{}.seiran", name));
+        if let Some(parent) = to.parent() {
+            let permission = permission.for_dir();
+            create_dir_all(parent, |dir| permission.apply(dir))?;
+        }
+        fs::remove_file(&tmp).ok();
+        symlink(entry, &tmp)?;
+        fs::rename(&tmp, &to)?;
+        Ok(())
+    }
+
+    pub fn generations(&self) -> Result<Vec<Generation>> {
+        match fs::File::open(self.data_dir.join(GENERATIONS)) {
+            Ok(file) => Ok(serde_json::from_reader(file)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save(&self, generations: &[Generation]) -> Result<()> {
+        fs::create_dir_all(&self.data_dir)?;
+        let path = self.data_dir.join(GENERATIONS);
+        let tmp = path.with_extension("json.tmp");
+        let file = fs::File::create(&tmp)?;
+        serde_json::to_writer_pretty(&file, generations)?;
+        file.sync_all()?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Record `entries` as a new generation, if they differ from the last one.
+    pub fn commit(&self, entries: BTreeMap<String, String>) -> Result<()> {
+        let mut generations = self.generations()?;
+        if generations.last().map(|last| &last.entries) == Some(&entries) {
+            return Ok(());
+        }
+        generations.push(Generation {
+            number: generations.last().map_or(1, |last| last.number + 1),
+            time: chrono::offset::Local::now().to_rfc3339(),
+            entries,
+        });
+        self.save(&generations)
+    }
+
+    /// Generation each file of the last one was installed in, as far back as the generations kept go.
+    pub fn installed_in(&self) -> Result<BTreeMap<String, u64>> {
+        let mut since = BTreeMap::new();
+        let mut last: Option<Generation> = None;
+        for generation in self.generations()? {
+            since = generation
+                .entries
+                .iter()
+                .map(|(name, entry)| {
+                    let unchanged = last.as_ref().and_then(|last| last.entries.get(name)) == Some(entry);
+                    let number = match since.get(name) {
+                        Some(&number) if unchanged => number,
+                        _ => generation.number,
+                    };
+                    (name.clone(), number)
+                })
+                .collect();
+            last = Some(generation);
+        }
+        Ok(since)
+    }
+
+    /// Drop all but the last `keep` generations, then the store entries none of them nor `install_dir` refers to.
+    pub fn gc(&self, keep: usize, install_dir: &path::Path) -> Result<Collected> {
+        let mut collected = Collected::default();
+        let mut generations = self.generations()?;
+        let drop = generations.len().saturating_sub(keep);
+        collected.generations = generations.drain(..drop).count();
+        if collected.generations > 0 {
+            self.save(&generations)?;
+        }
+        let mut live: HashSet<_> = generations
+            .iter()
+            .flat_map(|generation| generation.entries.values().cloned())
+            .collect();
+        // whatever is linked stays, even when a sync was interrupted before recording its generation
+        let dir = self.dir();
+        for target in links(install_dir)? {
+            if let (Ok(entry), Some(name)) = (target.strip_prefix(&dir), target.file_name()) {
+                if entry == path::Path::new(name) {
+                    live.insert(name.to_string_lossy().into_owned());
+                }
+            }
+        }
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(collected),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let entry = entry?;
+            if live.contains(&*entry.file_name().to_string_lossy()) {
+                continue;
+            }
+            log::debug!("remove {}", entry.path().display());
+            collected.bytes += entry.metadata()?.len();
+            fs::remove_file(entry.path())?;
+            collected.entries += 1;
+        }
+        Ok(collected)
+    }
+}
+
+/// Create `dir` and its missing parents, calling `init` on each of those created.
+fn create_dir_all(dir: &path::Path, init: impl Fn(&path::Path) -> Result<()>) -> Result<()> {
+    let existing = dir.ancestors().find(|dir| dir.exists()).map(path::Path::to_owned);
+    fs::create_dir_all(dir)?;
+    for dir in dir.ancestors().take_while(|dir| Some(*dir) != existing.as_deref()) {
+        init(dir)?;
+    }
+    Ok(())
+}
+
+/// Targets of the symlinks under `dir`, in the dirs below it too.
+fn links(dir: &path::Path) -> Result<Vec<path::PathBuf>> {
+    let mut targets = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        match fs::read_link(entry.path()) {
+            Ok(target) => targets.push(target),
+            Err(_) if entry.file_type()?.is_dir() => targets.extend(links(&entry.path())?),
+            Err(_) => {}
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gc_keeps_linked_entries() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("seiran-store-{}", std::process::id()));
+        let (data_dir, install_dir) = (dir.join("data"), dir.join("bin"));
+        fs::create_dir_all(&install_dir)?;
+        let store = Store::new(data_dir.clone().into(), data_dir.join(STORE_DIR).into());
+        let from = dir.join("ccc");
+        let mut entries = Vec::new();
+        for (i, content) in ["v1", "v2", "v3"].iter().enumerate() {
+            fs::write(&from, content)?;
+            let meta = Meta {
+                name: "dir/ccc".into(),
+                md5_hash: crate::check::md5_sum(&fs::File::open(&from)?)?,
+                ..Default::default()
+            };
+            let entry = store.add(&from, &meta, &Permission::default())?;
+            if i == 0 {
+                // linked by hand, outside of any generation
+                Store::link(&entry, &install_dir, path::Path::new("ccc"), &Permission::default())?;
+            } else {
+                store.commit(BTreeMap::from_iter([("ccc".to_owned(), Store::entry(&meta)?)]))?;
+            }
+            entries.push(entry);
+        }
+        let collected = store.gc(1, &install_dir)?;
+        assert_eq!((1, 1), (collected.generations, collected.entries));
+        assert!(entries[0].exists() && !entries[1].exists() && entries[2].exists());
+        assert_eq!(b"v1", &fs::read(install_dir.join("ccc"))?[..]);
+        assert_eq!(DIR_MODE, fs::metadata(store.dir())?.permissions().mode() & 0o777);
+        // an entry added again takes the permission configured now
+        fs::write(&from, "v1")?;
+        let meta = Meta {
+            name: "dir/ccc".into(),
+            md5_hash: crate::check::md5_sum(&fs::File::open(&from)?)?,
+            ..Default::default()
+        };
+        store.add(&from, &meta, &Permission::default().with_mode(0o700))?;
+        assert_eq!(0o700, fs::metadata(&entries[0])?.permissions().mode() & 0o777);
+        // the dirs of an install path take the permission of the file, searchable
+        Store::link(
+            &entries[2],
+            &install_dir,
+            path::Path::new("sbin/ccc"),
+            &Permission::default().with_mode(0o640),
+        )?;
+        assert_eq!(
+            0o750,
+            fs::metadata(install_dir.join("sbin"))?.permissions().mode() & 0o777
+        );
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,320 @@
+use crate::{
+    client::Client,
+    engine::{Aborted, SyncReport},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{fs, time::Duration};
+
+/// name of this host in notifications
+const HOSTNAME: &str = "/proc/sys/kernel/hostname";
+/// delay before the first retry, doubled for each one after
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+fn timeout() -> u64 {
+    10
+}
+
+fn retries() -> u32 {
+    3
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    /// the notification as is
+    #[default]
+    Generic,
+    /// `{"text": ...}` for Slack and the chat services compatible with its incoming webhooks
+    Slack,
+}
+
+/// A URL told about installs and failures, `[[webhooks]]` in the config.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Webhook {
+    pub url: String,
+    #[serde(default)]
+    pub format: Format,
+    /// seconds to wait for each attempt
+    #[serde(default = "timeout")]
+    pub timeout: u64,
+    /// attempts after the first failed one
+    #[serde(default = "retries")]
+    pub retries: u32,
+}
+
+impl Webhook {
+    /// Scheme, host and port of the URL, fit for logs: the path of a Slack URL is its secret.
+    pub fn origin(&self) -> String {
+        match url::Url::parse(&self.url) {
+            Ok(url) => url.origin().ascii_serialization(),
+            Err(_) => "invalid webhook url".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Installed<'n> {
+    pub name: String,
+    pub id: &'n str,
+    pub md5: &'n str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<&'n str>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Failed {
+    pub name: String,
+    pub error: String,
+}
+
+/// What a sync changed, posted as JSON.
+#[derive(Serialize, Debug)]
+pub struct Notification<'n> {
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<&'n str>,
+    pub installed: Vec<Installed<'n>>,
+    pub failed: Vec<Failed>,
+    /// why the sync failed as a whole, before getting to any object
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn hostname() -> String {
+    fs::read_to_string(HOSTNAME)
+        .map(|name| name.trim().to_owned())
+        .unwrap_or_else(|_| "unknown host".to_owned())
+}
+
+impl<'n> Notification<'n> {
+    pub fn new(source: Option<&'n str>, report: &'n SyncReport) -> Self {
+        Self {
+            host: hostname(),
+            source,
+            installed: report
+                .installed
+                .iter()
+                .map(|meta| Installed {
+                    name: meta.name(),
+                    id: &meta.id,
+                    md5: meta.content_md5(),
+                    version: meta.version(),
+                })
+                .collect(),
+            failed: report
+                .failed
+                .iter()
+                .map(|failure| Failed {
+                    name: failure.meta.name(),
+                    error: format!("{:#}", failure.error),
+                })
+                .collect(),
+            error: None,
+        }
+    }
+
+    /// A sync that failed with `error`, with what it did before when it got to the objects.
+    pub fn failure(source: Option<&'n str>, error: &'n anyhow::Error) -> Self {
+        match error.downcast_ref::<Aborted>() {
+            Some(aborted) => Self::new(source, &aborted.report),
+            None => Self {
+                host: hostname(),
+                source,
+                installed: Vec::new(),
+                failed: Vec::new(),
+                error: Some(format!("{:#}", error)),
+            },
+        }
+    }
+
+    /// Nothing installed nor failed, not worth a message.
+    pub fn is_empty(&self) -> bool {
+        self.installed.is_empty() && self.failed.is_empty() && self.error.is_none()
+    }
+
+    /// One line summary for chat.
+    fn text(&self) -> String {
+        let mut text = match self.source {
+            Some(source) => format!("{} ({}):", self.host, source),
+            None => format!("{}:", self.host),
+        };
+        if !self.installed.is_empty() {
+            let names: Vec<_> = self
+                .installed
+                .iter()
+                .map(|installed| match installed.version {
+                    Some(version) => format!("{} {}", installed.name, version),
+                    None => installed.name.clone(),
+                })
+                .collect();
+            text += &format!(" installed {}.", names.join(", "));
+        }
+        if !self.failed.is_empty() {
+            let failed: Vec<_> = self
+                .failed
+                .iter()
+                .map(|failed| format!("{} ({})", failed.name, failed.error))
+                .collect();
+            text += &format!(" failed {}.", failed.join(", "));
+        }
+        if let Some(ref error) = self.error {
+            text += &format!(" sync failed: {}.", error.trim_end_matches('.'));
+        }
+        text
+    }
+}
+
+/// Whether a later attempt may get through: the service is down or busy, not refusing this request.
+fn is_transient(e: &reqwest::Error) -> bool {
+    e.status()
+        .is_none_or(|status| status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS)
+}
+
+async fn post(client: &Client, webhook: &Webhook, body: &serde_json::Value) -> Result<()> {
+    let mut delay = RETRY_DELAY;
+    let mut attempt = 0;
+    loop {
+        let res = client
+            .post(&webhook.url)
+            .timeout(Duration::from_secs(webhook.timeout))
+            .json(body)
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(reqwest::Error::without_url);
+        match res {
+            Ok(_) => return Ok(()),
+            Err(e) if attempt < webhook.retries && is_transient(&e) => {
+                log::debug!("post to {}: {}, retry in {:?}", webhook.origin(), e, delay);
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Post `notification` to every webhook through `client`, a failing one does not keep the others from it.
+pub async fn notify(client: &Client, webhooks: &[Webhook], notification: &Notification<'_>) -> Result<()> {
+    let mut res = Ok(());
+    for webhook in webhooks {
+        let body = match webhook.format {
+            Format::Generic => serde_json::to_value(notification)?,
+            Format::Slack => json!({ "text": notification.text() }),
+        };
+        if let Err(e) = post(client, webhook, &body).await {
+            res = Err(anyhow::Error::msg(format!("{}: {}", webhook.origin(), e)));
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{engine::Failure, meta::Meta};
+    use hyper::{
+        service::{make_service_fn, service_fn},
+        Body, Response, Server, StatusCode,
+    };
+    use std::{
+        convert::Infallible,
+        sync::{Arc, Mutex},
+    };
+
+    #[test]
+    fn retry_until_delivered() -> anyhow::Result<()> {
+        let rt = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let addr = {
+            let received = received.clone();
+            let _guard = rt.enter();
+            // the first request of each format fails
+            let make_svc = make_service_fn(move |_| {
+                let received = received.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |req| {
+                        let received = received.clone();
+                        async move {
+                            let revoked = req.uri().path() == "/revoked";
+                            let body = hyper::body::to_bytes(req.into_body()).await.unwrap_or_default();
+                            let mut received = received.lock().unwrap();
+                            received.push(serde_json::from_slice::<serde_json::Value>(&body).unwrap_or_default());
+                            let status = if revoked {
+                                StatusCode::NOT_FOUND
+                            } else if received.len() % 2 == 1 {
+                                StatusCode::INTERNAL_SERVER_ERROR
+                            } else {
+                                StatusCode::OK
+                            };
+                            Ok::<_, Infallible>(Response::builder().status(status).body(Body::empty()).unwrap())
+                        }
+                    }))
+                }
+            });
+            let server = Server::try_bind(&"127.0.0.1:0".parse()?)?.serve(make_svc);
+            let addr = server.local_addr();
+            rt.spawn(server);
+            addr
+        };
+        let webhook = |format| Webhook {
+            url: format!("http://{}/hook", addr),
+            format,
+            timeout: 1,
+            retries: 1,
+        };
+        let report = SyncReport {
+            installed: vec![Meta {
+                name: "dir/ccc".into(),
+                id: "bkt/dir/ccc/1".into(),
+                md5_hash: "aaa".into(),
+                ..Default::default()
+            }],
+            failed: vec![Failure {
+                meta: Meta {
+                    name: "dir/ddd".into(),
+                    ..Default::default()
+                },
+                error: anyhow::Error::msg("Check_sum failed."),
+            }],
+            ..Default::default()
+        };
+        let notification = Notification::new(Some("tools"), &report);
+        let client = Client::new(&Default::default())?;
+        rt.block_on(notify(
+            &client,
+            &[webhook(Format::Generic), webhook(Format::Slack)],
+            &notification,
+        ))?;
+        let bodies = received.lock().unwrap();
+        assert_eq!(4, bodies.len());
+        assert_eq!("ccc", bodies[1]["installed"][0]["name"]);
+        assert_eq!("Check_sum failed.", bodies[1]["failed"][0]["error"]);
+        assert!(bodies[3]["text"]
+            .as_str()
+            .is_some_and(|text| text.ends_with("(tools): installed ccc. failed ddd (Check_sum failed.).")));
+        drop(bodies);
+        // a refused webhook is not asked again
+        let mut revoked = webhook(Format::Generic);
+        revoked.url = format!("http://{}/revoked", addr);
+        assert!(rt.block_on(notify(&client, &[revoked], &notification)).is_err());
+        assert_eq!(5, received.lock().unwrap().len());
+        let error = anyhow::Error::msg("Every mirror is in cooldown.");
+        let failure = Notification::failure(None, &error);
+        assert!(failure.text().ends_with(": sync failed: Every mirror is in cooldown."));
+        let mut unreachable = webhook(Format::Generic);
+        unreachable.retries = 0;
+        unreachable.url = "http://127.0.0.1:1/hook".into();
+        let error = rt
+            .block_on(notify(&client, &[unreachable], &notification))
+            .expect_err("nothing listens on port 1");
+        assert!(error.to_string().starts_with("http://127.0.0.1:1: ") && !error.to_string().contains("/hook"));
+        drop(notification);
+        let aborted = anyhow::Error::from(Aborted { report });
+        assert_eq!(1, Notification::failure(None, &aborted).failed.len());
+        Ok(())
+    }
+}